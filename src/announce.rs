@@ -0,0 +1,44 @@
+use crate::config::{AzolveConfig, WebhookConfig};
+use crate::db::{Membership, OrgDbClient};
+use crate::types::*;
+use crate::webhooks;
+use crate::writeback;
+
+// Every way of linking, unlinking or kicking a membership, whether on the website, through the
+// admin API, from the command line or in the background, tells the webhook endpoints and, when the
+// backend supports it, the membership system.
+
+// After `membership` was linked, or linked again by the same account when `renewing`.
+pub async fn linked(
+    db: &OrgDbClient,
+    webhook_config: &WebhookConfig,
+    azolve: &AzolveConfig,
+    membership: &Membership,
+    renewing: bool,
+) -> Result<(), ErrorBox> {
+    webhooks::notify(
+        db,
+        webhook_config,
+        if renewing {
+            webhooks::RENEWED
+        } else {
+            webhooks::LINKED
+        },
+        membership,
+        None,
+    )
+    .await?;
+    writeback::enqueue(db, azolve, &membership.org_id, &membership.lichess_id).await
+}
+
+// After `membership` ended; `event` is `webhooks::UNLINKED` or `webhooks::KICKED`.
+pub async fn ended(
+    db: &OrgDbClient,
+    webhook_config: &WebhookConfig,
+    azolve: &AzolveConfig,
+    event: &str,
+    membership: &Membership,
+) -> Result<(), ErrorBox> {
+    webhooks::notify(db, webhook_config, event, membership, None).await?;
+    writeback::enqueue(db, azolve, &membership.org_id, "").await
+}
@@ -0,0 +1,350 @@
+use crate::announce;
+use crate::apikey::{self, ApiKey};
+use crate::config::Config;
+use crate::db::{self, Membership, OrgDbClient, ReferralStat, Registration};
+use crate::memberhook::{self, UpdateResult};
+use crate::org::{self, MembershipState};
+use crate::tempctx::{MemberRow, make_member_row};
+use crate::webhooks;
+use crate::{ErrorStatus, to_500};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{Request, Route, State, catch, catchers, delete, get, post, routes};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+
+pub const BASE: &str = "/api/v1";
+
+type ApiResult<T> = Result<Result<Json<T>, Status>, ErrorStatus>;
+
+#[derive(Deserialize)]
+pub struct LinkRequest {
+    org_id: String,
+    lichess_id: String,
+    exp_year: Option<i32>,
+}
+
+#[derive(Deserialize)]
+pub struct ExtendRequest {
+    exp_year: i32,
+}
+
+#[derive(Deserialize)]
+pub struct LookupRequest {
+    lichess_ids: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct LookupResult {
+    lichess_id: String,
+    linked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    member_id: Option<String>,
+    exp_year: Option<i32>,
+    state: Option<MembershipState>,
+}
+
+#[derive(Serialize)]
+pub struct KickResponse {
+    result: UpdateResult,
+}
+
+#[derive(Serialize)]
+pub struct ReferralsResponse {
+    clicks: i64,
+    by_month: Vec<ReferralStat>,
+    by_source: Vec<ReferralStat>,
+}
+
+fn member_row(config: &Config, membership: Membership) -> Result<MemberRow, ErrorStatus> {
+    let timezone = org::timezone_from_string(&config.org.timezone).map_err(to_500)?;
+    Ok(make_member_row(membership, timezone, &config.expiry))
+}
+
+async fn audit(
+    db: &OrgDbClient,
+    event: &str,
+    key: &ApiKey,
+    membership: &Membership,
+) -> Result<(), ErrorStatus> {
+    db.audit(
+        event,
+        Some(&membership.lichess_id),
+        Some(&membership.org_id),
+        &format!("key={}", &key.name),
+    )
+    .await
+    .map_err(to_500)?;
+    Ok(())
+}
+
+#[get("/members")]
+async fn list_members(
+    key: ApiKey,
+    config: &State<Config>,
+    db: &State<OrgDbClient>,
+) -> ApiResult<Vec<MemberRow>> {
+    if !key.allows(apikey::MEMBERS_READ) {
+        return Ok(Err(Status::Forbidden));
+    }
+    let mut rows = vec![];
+    for membership in db.get_members().await.map_err(to_500)? {
+        rows.push(member_row(config, membership)?);
+    }
+    Ok(Ok(Json(rows)))
+}
+
+#[get("/members/<org_id>")]
+async fn get_member(
+    org_id: &str,
+    key: ApiKey,
+    config: &State<Config>,
+    db: &State<OrgDbClient>,
+) -> ApiResult<MemberRow> {
+    if !key.allows(apikey::MEMBERS_READ) {
+        return Ok(Err(Status::Forbidden));
+    }
+    match db.get_member_for_org_id(org_id).await.map_err(to_500)? {
+        Some(membership) => Ok(Ok(Json(member_row(config, membership)?))),
+        None => Ok(Err(Status::NotFound)),
+    }
+}
+
+// Checks a list of Lichess accounts at once, e.g. the entrants of a tournament. Member IDs are only
+// included for keys that can also read members.
+#[post("/members/lookup", data = "<request>")]
+async fn look_up_members(
+    request: Json<LookupRequest>,
+    key: ApiKey,
+    config: &State<Config>,
+    db: &State<OrgDbClient>,
+) -> ApiResult<Vec<LookupResult>> {
+    if !key.allows(apikey::MEMBERS_LOOKUP) {
+        return Ok(Err(Status::Forbidden));
+    }
+    if request.lichess_ids.len() > config.api.max_lookup_ids {
+        return Ok(Err(Status::PayloadTooLarge));
+    }
+    let lichess_ids: Vec<String> = request
+        .lichess_ids
+        .iter()
+        .map(|id| id.trim().to_lowercase())
+        .collect();
+    let mut members = HashMap::new();
+    for membership in db
+        .get_members_for_lichess_ids(&lichess_ids)
+        .await
+        .map_err(to_500)?
+    {
+        members.insert(
+            membership.lichess_id.clone(),
+            member_row(config, membership)?,
+        );
+    }
+
+    let show_member_id = key.allows(apikey::MEMBERS_READ);
+    Ok(Ok(Json(
+        lichess_ids
+            .into_iter()
+            .map(|lichess_id| match members.get(&lichess_id) {
+                Some(row) => LookupResult {
+                    linked: true,
+                    member_id: show_member_id.then(|| row.membership.org_id.clone()),
+                    exp_year: Some(row.membership.exp_year),
+                    state: Some(row.state),
+                    lichess_id,
+                },
+                None => LookupResult {
+                    lichess_id,
+                    linked: false,
+                    member_id: None,
+                    exp_year: None,
+                    state: None,
+                },
+            })
+            .collect(),
+    )))
+}
+
+// Links without verification or joining the team, like the link command. As there, an existing
+// link of either the member ID or the Lichess account is replaced.
+#[post("/members", data = "<request>")]
+async fn link_member(
+    request: Json<LinkRequest>,
+    key: ApiKey,
+    config: &State<Config>,
+    db: &State<OrgDbClient>,
+) -> ApiResult<MemberRow> {
+    if !key.allows(apikey::MEMBERS_WRITE) {
+        return Ok(Err(Status::Forbidden));
+    }
+    let timezone = org::timezone_from_string(&config.org.timezone).map_err(to_500)?;
+    let exp_year = match request.exp_year {
+        Some(year) if !org::is_plausible_exp_year(year, timezone) => {
+            return Ok(Err(Status::BadRequest));
+        }
+        Some(year) => year,
+        None => org::new_membership_exp_year(
+            timezone,
+            config.expiry.membership_month,
+            config.expiry.membership_day,
+        ),
+    };
+    let membership = Membership {
+        org_id: request.org_id.clone(),
+        lichess_id: request.lichess_id.to_lowercase(),
+        exp_year,
+    };
+    let renewing = db
+        .get_member_for_lichess_id(&membership.lichess_id)
+        .await
+        .map_err(to_500)?
+        .is_some_and(|current| current.org_id == membership.org_id);
+    match db
+        .register_member(&membership.org_id, &membership.lichess_id, exp_year)
+        .await
+        .map_err(to_500)?
+    {
+        Registration::Registered => {}
+        _ => return Ok(Err(Status::Conflict)),
+    }
+
+    audit(db, "api_link", &key, &membership).await?;
+    announce::linked(db, &config.webhooks, &config.azolve, &membership, renewing)
+        .await
+        .map_err(to_500)?;
+    Ok(Ok(Json(member_row(config, membership)?)))
+}
+
+// Removes the link without kicking from the team, like the unlink command.
+#[delete("/members/<org_id>")]
+async fn unlink_member(
+    org_id: &str,
+    key: ApiKey,
+    config: &State<Config>,
+    db: &State<OrgDbClient>,
+) -> ApiResult<Membership> {
+    if !key.allows(apikey::MEMBERS_WRITE) {
+        return Ok(Err(Status::Forbidden));
+    }
+    let Some(membership) = db.get_member_for_org_id(org_id).await.map_err(to_500)? else {
+        return Ok(Err(Status::NotFound));
+    };
+    if db
+        .end_membership(org_id, db::ENDED_UNLINKED)
+        .await
+        .map_err(to_500)?
+        == 0
+    {
+        return Ok(Err(Status::NotFound));
+    }
+
+    audit(db, "api_unlink", &key, &membership).await?;
+    announce::ended(
+        db,
+        &config.webhooks,
+        &config.azolve,
+        webhooks::UNLINKED,
+        &membership,
+    )
+    .await
+    .map_err(to_500)?;
+    Ok(Ok(Json(membership)))
+}
+
+#[post("/members/<org_id>/extend", data = "<request>")]
+async fn extend_member(
+    org_id: &str,
+    request: Json<ExtendRequest>,
+    key: ApiKey,
+    config: &State<Config>,
+    db: &State<OrgDbClient>,
+) -> ApiResult<MemberRow> {
+    if !key.allows(apikey::MEMBERS_WRITE) {
+        return Ok(Err(Status::Forbidden));
+    }
+    let timezone = org::timezone_from_string(&config.org.timezone).map_err(to_500)?;
+    if !org::is_plausible_exp_year(request.exp_year, timezone) {
+        return Ok(Err(Status::BadRequest));
+    }
+    let Some(membership) = db.get_member_for_org_id(org_id).await.map_err(to_500)? else {
+        return Ok(Err(Status::NotFound));
+    };
+    db.set_expiry_year(org_id, request.exp_year)
+        .await
+        .map_err(to_500)?;
+    let membership = Membership {
+        exp_year: request.exp_year,
+        ..membership
+    };
+
+    audit(db, "api_extend", &key, &membership).await?;
+    webhooks::notify(db, &config.webhooks, webhooks::RENEWED, &membership, None)
+        .await
+        .map_err(to_500)?;
+    Ok(Ok(Json(member_row(config, membership)?)))
+}
+
+#[post("/members/<org_id>/kick")]
+async fn kick_member(
+    org_id: &str,
+    key: ApiKey,
+    config: &State<Config>,
+    db: &State<OrgDbClient>,
+    http_client: &State<reqwest::Client>,
+) -> ApiResult<KickResponse> {
+    if !key.allows(apikey::MEMBERS_KICK) {
+        return Ok(Err(Status::Forbidden));
+    }
+    let Some(membership) = db.get_member_for_org_id(org_id).await.map_err(to_500)? else {
+        return Ok(Err(Status::NotFound));
+    };
+    audit(db, "api_kick", &key, &membership).await?;
+    let result = memberhook::kick_or_queue(db, http_client, config, membership)
+        .await
+        .map_err(to_500)?;
+    Ok(Ok(Json(KickResponse { result })))
+}
+
+#[get("/referrals")]
+async fn referrals(
+    key: ApiKey,
+    config: &State<Config>,
+    db: &State<OrgDbClient>,
+) -> ApiResult<ReferralsResponse> {
+    if !key.allows(apikey::REFERRALS_READ) {
+        return Ok(Err(Status::Forbidden));
+    }
+    Ok(Ok(Json(ReferralsResponse {
+        clicks: db.referral_count().await.map_err(to_500)?,
+        by_month: db
+            .referral_stats_by_month(&config.org.timezone)
+            .await
+            .map_err(to_500)?,
+        by_source: db.referral_stats_by_source().await.map_err(to_500)?,
+    })))
+}
+
+// API clients get errors as JSON instead of the HTML error pages.
+#[catch(default)]
+fn error(status: Status, _request: &Request) -> Json<Value> {
+    Json(json!({ "error": status.reason_lossy() }))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        list_members,
+        get_member,
+        look_up_members,
+        link_member,
+        unlink_member,
+        extend_member,
+        kick_member,
+        referrals
+    ]
+}
+
+pub fn catchers() -> Vec<rocket::Catcher> {
+    catchers![error]
+}
@@ -0,0 +1,80 @@
+use crate::db::OrgDbClient;
+use crate::randstr;
+use crate::types::*;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::{Request, State};
+use sha2::{Digest, Sha256};
+
+pub const MEMBERS_READ: &str = "members:read";
+pub const MEMBERS_WRITE: &str = "members:write";
+pub const MEMBERS_KICK: &str = "members:kick";
+pub const MEMBERS_LOOKUP: &str = "members:lookup";
+pub const REFERRALS_READ: &str = "referrals:read";
+
+pub const SCOPES: &[&str] = &[
+    MEMBERS_READ,
+    MEMBERS_WRITE,
+    MEMBERS_KICK,
+    MEMBERS_LOOKUP,
+    REFERRALS_READ,
+];
+
+const KEY_PREFIX: &str = "o2l_";
+const DISPLAY_PREFIX_LENGTH: usize = 8;
+
+pub fn is_valid_scope(scope: &str) -> bool {
+    SCOPES.contains(&scope)
+}
+
+pub fn hash(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+// A new key, and the prefix that is shown for it. The key itself is only shown once.
+pub fn generate() -> Result<(String, String), ErrorBox> {
+    let key = format!("{}{}", KEY_PREFIX, &randstr::random_string()?[..40]);
+    let prefix = key[..KEY_PREFIX.len() + DISPLAY_PREFIX_LENGTH].to_string();
+    Ok((key, prefix))
+}
+
+// The API key a request was made with, from an "Authorization: Bearer <key>" header.
+pub struct ApiKey {
+    pub name: String,
+    pub scopes: Vec<String>,
+}
+
+impl ApiKey {
+    pub fn allows(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ApiKey {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<ApiKey, Self::Error> {
+        let Some(key) = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "))
+        else {
+            return Outcome::Error((Status::Unauthorized, ()));
+        };
+        let Outcome::Success(db) = request.guard::<&State<OrgDbClient>>().await else {
+            return Outcome::Error((Status::InternalServerError, ()));
+        };
+        match db.use_api_key(&hash(key.trim())).await {
+            Ok(Some(record)) => Outcome::Success(ApiKey {
+                name: record.name,
+                scopes: record.scopes,
+            }),
+            Ok(None) => Outcome::Error((Status::Unauthorized, ())),
+            Err(e) => {
+                println!("Could not check API key: {}", e);
+                Outcome::Error((Status::InternalServerError, ()))
+            }
+        }
+    }
+}
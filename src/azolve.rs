@@ -1,4 +1,5 @@
 use crate::types::*;
+use chrono::NaiveDate;
 use reqwest::{Client, Request};
 use reqwest::{Method, Url};
 
@@ -10,18 +11,12 @@ pub async fn verify_user(
     azolve_password: &str,
     azolve_token: &str,
     auth_secret: &str,
-    test_backdoor_member_id: &str,
-    test_backdoor_password: &str,
 ) -> Result<bool, ErrorBox> {
-    if member_id == test_backdoor_member_id && member_password == test_backdoor_password {
-        return Ok(true);
-    }
-
     let mut url = Url::parse(azolve_url)?;
     {
         let mut query = url.query_pairs_mut();
         query.append_pair("userId", "AzolveAPI");
-        query.append_pair("password", &azolve_password);
+        query.append_pair("password", azolve_password);
         query.append_pair("clientReference", "ECF");
         query.append_pair("objectName", "Cus_SSO_Pin");
         query.append_pair("objectType", "sp");
@@ -38,3 +33,77 @@ pub async fn verify_user(
     println!("{}", response);
     Ok(response.trim() == "[[\"Return Code\",\"Message\"],[\"1\",\"Success\"]]")
 }
+
+// Looks up the expiry date of a membership with the API token alone. `object_name` is a stored
+// procedure that takes the member ID and returns a table with an "Expiry" column (YYYY-MM-DD),
+// e.g. [["Return Code","Message","Expiry"],["1","Success","2026-08-31"]].
+pub async fn lookup_expiry(
+    http_client: &Client,
+    member_id: &str,
+    azolve_url: &str,
+    azolve_password: &str,
+    azolve_token: &str,
+    object_name: &str,
+) -> Result<Option<NaiveDate>, ErrorBox> {
+    let mut url = Url::parse(azolve_url)?;
+    {
+        let mut query = url.query_pairs_mut();
+        query.append_pair("userId", "AzolveAPI");
+        query.append_pair("password", azolve_password);
+        query.append_pair("clientReference", "ECF");
+        query.append_pair("objectName", object_name);
+        query.append_pair("objectType", "sp");
+        query.append_pair(
+            "parameters",
+            &format!("MID|{};Token|{}", member_id, azolve_token),
+        );
+    }
+    let req = Request::new(Method::GET, url);
+    let table: Vec<Vec<String>> = http_client.execute(req).await?.json().await?;
+    let (Some(header), Some(row)) = (table.first(), table.get(1)) else {
+        return Ok(None);
+    };
+    if row.first().map(String::as_str) != Some("1") {
+        return Ok(None);
+    }
+    let column = header
+        .iter()
+        .position(|name| name == "Expiry")
+        .ok_or("Azolve lookup returned no Expiry column")?;
+    let expiry = row
+        .get(column)
+        .ok_or("Azolve lookup returned a short row")?;
+    Ok(Some(NaiveDate::parse_from_str(expiry.trim(), "%Y-%m-%d")?))
+}
+
+// Stores the Lichess ID linked to a member, or clears it when `lichess_id` is empty. `object_name` is
+// a stored procedure that answers like the PIN check.
+pub async fn write_lichess_id(
+    http_client: &Client,
+    member_id: &str,
+    lichess_id: &str,
+    azolve_url: &str,
+    azolve_password: &str,
+    azolve_token: &str,
+    object_name: &str,
+) -> Result<bool, ErrorBox> {
+    let mut url = Url::parse(azolve_url)?;
+    {
+        let mut query = url.query_pairs_mut();
+        query.append_pair("userId", "AzolveAPI");
+        query.append_pair("password", azolve_password);
+        query.append_pair("clientReference", "ECF");
+        query.append_pair("objectName", object_name);
+        query.append_pair("objectType", "sp");
+        query.append_pair(
+            "parameters",
+            &format!(
+                "MID|{};Lichess|{};Token|{}",
+                member_id, lichess_id, azolve_token
+            ),
+        );
+    }
+    let req = Request::new(Method::GET, url);
+    let response = http_client.execute(req).await?.text().await?;
+    Ok(response.trim() == "[[\"Return Code\",\"Message\"],[\"1\",\"Success\"]]")
+}
@@ -0,0 +1,363 @@
+use crate::announce;
+use crate::config::Config;
+use crate::db::{self, Membership, OrgDbClient, Registration};
+use crate::expwatch;
+use crate::grandprix;
+use crate::i18n::{self, Catalogue};
+use crate::leader::Leadership;
+use crate::memberhook::{self, UpdateResult};
+use crate::org;
+use crate::retention;
+use crate::reverify;
+use crate::types::*;
+use crate::verifier;
+use crate::webhooks;
+use crate::writeback;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+pub const USAGE: &str = "Usage: org2lichess [--config <file>] [COMMAND]
+
+The configuration file defaults to $ORG2LICHESS_CONFIG, or Config.toml.
+
+Commands:
+  serve                                    Run the web server (default)
+  migrate                                  Create or update the database tables
+  check-config                             Validate the configuration and report all problems
+  list-members                             List all linked memberships
+  link <org-id> <lichess-id> [exp-year]    Link a membership without verification
+  unlink <lichess-id>                      Remove a link without kicking from the team
+  kick <lichess-id>                        Kick from the Lichess team and remove the link, or queue the kick
+  run-expiry [--dry-run]                   Queue kicks for expired members and process due kicks
+  run-retention                            Delete or anonymise records past their retention period
+  run-reverification                       Look up expiring members and extend renewed memberships
+  refresh-grand-prix                       Fetch the results of finished Grand Prix tournaments
+  backfill-writeback                       Write the Lichess IDs of all linked members back
+  export [file]                            Export memberships and referrals as JSON
+  import <file>                            Import memberships and referrals from JSON";
+
+pub enum Command {
+    Serve,
+    Migrate,
+    CheckConfig,
+    ListMembers,
+    Link {
+        org_id: String,
+        lichess_id: String,
+        exp_year: Option<i32>,
+    },
+    Unlink {
+        lichess_id: String,
+    },
+    Kick {
+        lichess_id: String,
+    },
+    RunExpiry {
+        dry_run: bool,
+    },
+    RunRetention,
+    RunReverification,
+    RefreshGrandPrix,
+    BackfillWriteback,
+    Export {
+        path: Option<String>,
+    },
+    Import {
+        path: String,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExportData {
+    memberships: Vec<Membership>,
+    referrals: Vec<String>,
+}
+
+fn required(arg: Option<&String>, name: &str) -> Result<String, ErrorBox> {
+    Ok(arg.ok_or(format!("Missing argument: {}", name))?.clone())
+}
+
+pub fn take_config_path(args: &mut Vec<String>) -> Result<Option<String>, ErrorBox> {
+    match args.iter().position(|a| a == "--config") {
+        Some(i) => {
+            if i + 1 >= args.len() {
+                return Err("Missing argument: --config <file>".into());
+            }
+            let path = args.remove(i + 1);
+            args.remove(i);
+            Ok(Some(path))
+        }
+        None => Ok(None),
+    }
+}
+
+pub fn parse(args: &[String]) -> Result<Command, ErrorBox> {
+    let command = match args.first().map(|a| a.as_str()) {
+        None | Some("serve") => Command::Serve,
+        Some("migrate") => Command::Migrate,
+        Some("check-config") => Command::CheckConfig,
+        Some("list-members") => Command::ListMembers,
+        Some("link") => Command::Link {
+            org_id: required(args.get(1), "org-id")?,
+            lichess_id: required(args.get(2), "lichess-id")?.to_lowercase(),
+            exp_year: match args.get(3) {
+                Some(year) => Some(year.parse()?),
+                None => None,
+            },
+        },
+        Some("unlink") => Command::Unlink {
+            lichess_id: required(args.get(1), "lichess-id")?.to_lowercase(),
+        },
+        Some("kick") => Command::Kick {
+            lichess_id: required(args.get(1), "lichess-id")?.to_lowercase(),
+        },
+        Some("run-expiry") => match args.get(1).map(|a| a.as_str()) {
+            None => Command::RunExpiry { dry_run: false },
+            Some("--dry-run") => Command::RunExpiry { dry_run: true },
+            Some(other) => return Err(format!("Unknown option: {}", other).into()),
+        },
+        Some("run-retention") => Command::RunRetention,
+        Some("run-reverification") => Command::RunReverification,
+        Some("refresh-grand-prix") => Command::RefreshGrandPrix,
+        Some("backfill-writeback") => Command::BackfillWriteback,
+        Some("export") => Command::Export {
+            path: args.get(1).cloned(),
+        },
+        Some("import") => Command::Import {
+            path: required(args.get(1), "file")?,
+        },
+        Some(other) => return Err(format!("Unknown command: {}", other).into()),
+    };
+    Ok(command)
+}
+
+pub async fn run(command: Command, config: Config) -> Result<(), ErrorBox> {
+    if let Command::CheckConfig = command {
+        Catalogue::load(i18n::LOCALES_DIRECTORY, &config.org, &config.lichess.domain)?;
+        println!("Configuration is valid.");
+        return Ok(());
+    }
+
+    let db = db::connect(&config.server.postgres_options).await?;
+
+    match command {
+        Command::CheckConfig => {}
+        Command::Serve => crate::serve(config, db).await?,
+        Command::Migrate => {
+            db.migrate().await?;
+            println!("Database is up to date.");
+        }
+        Command::ListMembers => {
+            for member in db.get_members().await? {
+                println!(
+                    "{}\t{}\t{}",
+                    member.org_id, member.lichess_id, member.exp_year
+                );
+            }
+        }
+        Command::Link {
+            org_id,
+            lichess_id,
+            exp_year,
+        } => link(&config, &db, &org_id, &lichess_id, exp_year).await?,
+        Command::Unlink { lichess_id } => unlink(&config, &db, &lichess_id).await?,
+        Command::Kick { lichess_id } => kick(&config, &db, &lichess_id).await?,
+        Command::RunExpiry { dry_run } => run_expiry(&config, &db, dry_run).await?,
+        Command::RunRetention => retention::purge(&db, &config.retention).await?,
+        Command::RunReverification => {
+            if !verifier::can_look_up(&config.azolve, &config.testing) {
+                return Err("azolve.lookup_object must be set to look members up".into());
+            }
+            reverify::reverify(
+                &db,
+                &reqwest::Client::new(),
+                &reverify::ReverifySettings::from_config(&config)?,
+            )
+            .await?
+        }
+        Command::RefreshGrandPrix => {
+            grandprix::refresh(
+                &db,
+                &reqwest::Client::new(),
+                &grandprix::GrandPrixSettings::from_config(&config)?,
+            )
+            .await?
+        }
+        Command::BackfillWriteback => backfill_writeback(&config, &db).await?,
+        Command::Export { path } => export(&db, path.as_deref()).await?,
+        Command::Import { path } => import(&db, &path).await?,
+    }
+    Ok(())
+}
+
+async fn link(
+    config: &Config,
+    db: &OrgDbClient,
+    org_id: &str,
+    lichess_id: &str,
+    exp_year: Option<i32>,
+) -> Result<(), ErrorBox> {
+    let timezone = org::timezone_from_string(&config.org.timezone)?;
+    let exp_year = match exp_year {
+        Some(year) if !org::is_plausible_exp_year(year, timezone) => {
+            let current = org::current_year(timezone);
+            return Err(format!(
+                "exp-year must lie between {} and {}",
+                current - 1,
+                current + 10
+            )
+            .into());
+        }
+        Some(year) => year,
+        None => org::new_membership_exp_year(
+            timezone,
+            config.expiry.membership_month,
+            config.expiry.membership_day,
+        ),
+    };
+    let renewing = db
+        .get_member_for_lichess_id(lichess_id)
+        .await?
+        .is_some_and(|current| current.org_id == org_id);
+    if let Registration::AlreadyLinked = db.register_member(org_id, lichess_id, exp_year).await? {
+        return Err(format!("{} or {} was linked concurrently", org_id, lichess_id).into());
+    }
+    let membership = Membership {
+        org_id: org_id.to_string(),
+        lichess_id: lichess_id.to_string(),
+        exp_year,
+    };
+    announce::linked(db, &config.webhooks, &config.azolve, &membership, renewing).await?;
+    println!("Linked {} to {} until {}", org_id, lichess_id, exp_year);
+    Ok(())
+}
+
+async fn linked_member(db: &OrgDbClient, lichess_id: &str) -> Result<Membership, ErrorBox> {
+    db.get_member_for_lichess_id(lichess_id)
+        .await?
+        .ok_or_else(|| format!("{} is not linked", lichess_id).into())
+}
+
+async fn unlink(config: &Config, db: &OrgDbClient, lichess_id: &str) -> Result<(), ErrorBox> {
+    let membership = linked_member(db, lichess_id).await?;
+    if db
+        .end_membership(&membership.org_id, db::ENDED_UNLINKED)
+        .await?
+        == 0
+    {
+        return Err(format!("{} is not linked", lichess_id).into());
+    }
+    announce::ended(
+        db,
+        &config.webhooks,
+        &config.azolve,
+        webhooks::UNLINKED,
+        &membership,
+    )
+    .await?;
+    println!("Unlinked {}", lichess_id);
+    Ok(())
+}
+
+// Queues every linked member and writes them back right away; failures stay queued for the server.
+async fn backfill_writeback(config: &Config, db: &OrgDbClient) -> Result<(), ErrorBox> {
+    if !verifier::can_write_back(&config.azolve) {
+        return Err("azolve.write_object must be set to write Lichess IDs back".into());
+    }
+    let queued = db.enqueue_all_writebacks().await?;
+    println!("Queued {} member(s)", queued);
+    let written = writeback::process_writeback_queue(
+        db,
+        &reqwest::Client::new(),
+        &writeback::WritebackSettings {
+            azolve: config.azolve.clone(),
+            queue: config.writeback.clone(),
+        },
+        &Leadership::always(),
+    )
+    .await?;
+    println!("Wrote {} Lichess ID(s) back", written);
+    Ok(())
+}
+
+// Kicks like the admin API does, leaving the kick to the kick queue if Lichess cannot be reached.
+async fn kick(config: &Config, db: &OrgDbClient, lichess_id: &str) -> Result<(), ErrorBox> {
+    let membership = linked_member(db, lichess_id).await?;
+    match memberhook::kick_or_queue(db, &reqwest::Client::new(), config, membership).await? {
+        UpdateResult::Kicked => println!("Kicked {}", lichess_id),
+        UpdateResult::Queued => println!("Could not kick {}; queued the kick", lichess_id),
+        _ => println!(
+            "Could not kick {}; the kick queue already has it",
+            lichess_id
+        ),
+    }
+    Ok(())
+}
+
+async fn run_expiry(config: &Config, db: &OrgDbClient, dry_run: bool) -> Result<(), ErrorBox> {
+    let timezone = org::timezone_from_string(&config.org.timezone)?;
+
+    if dry_run {
+        let expired = expwatch::find_expired_members(
+            db,
+            timezone,
+            config.expiry.renewal_month,
+            config.expiry.renewal_day,
+        )
+        .await?;
+        for member in &expired {
+            println!(
+                "Would kick {} ({}, expired {})",
+                member.lichess_id, member.org_id, member.exp_year
+            );
+        }
+        println!("{} member(s) would be kicked.", expired.len());
+    } else {
+        let queued = expwatch::enqueue_expired_members(
+            db,
+            timezone,
+            config.expiry.renewal_month,
+            config.expiry.renewal_day,
+        )
+        .await?;
+        println!("Queued {} expired member(s) to be kicked.", queued);
+        expwatch::process_kick_queue(
+            db,
+            &reqwest::Client::new(),
+            &expwatch::KickSettings::from_config(config),
+            &Leadership::always(),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+async fn export(db: &OrgDbClient, path: Option<&str>) -> Result<(), ErrorBox> {
+    let data = ExportData {
+        memberships: db.get_members().await?,
+        referrals: db.get_referrals().await?,
+    };
+    let json = serde_json::to_string_pretty(&data)?;
+    match path {
+        Some(path) => fs::write(path, json)?,
+        None => println!("{}", json),
+    }
+    Ok(())
+}
+
+async fn import(db: &OrgDbClient, path: &str) -> Result<(), ErrorBox> {
+    let data: ExportData = serde_json::from_str(&fs::read_to_string(path)?)?;
+    let skipped = db.import(&data.memberships, &data.referrals).await?;
+    for org_id in &skipped {
+        println!(
+            "Skipped {}: it or its Lichess account is already linked",
+            org_id
+        );
+    }
+    println!(
+        "Imported {} membership(s) and {} referral(s).",
+        data.memberships.len() - skipped.len(),
+        data.referrals.len()
+    );
+    Ok(())
+}
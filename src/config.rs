@@ -1,4 +1,15 @@
+use crate::org;
+use crate::retention;
+use crate::schedule;
+use crate::types::*;
+use crate::verifier;
+use chrono::NaiveDate;
+use regex::Regex;
+use reqwest::Url;
 use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use toml::{Table, Value};
 
 #[derive(Deserialize)]
 pub struct Config {
@@ -7,6 +18,28 @@ pub struct Config {
     pub server: ServerConfig,
     pub lichess: LichessConfig,
     pub azolve: AzolveConfig,
+    #[serde(default)]
+    pub kick_queue: KickQueueConfig,
+    #[serde(default)]
+    pub link_limit: LinkLimitConfig,
+    #[serde(default)]
+    pub leader_election: LeaderElectionConfig,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+    #[serde(default)]
+    pub webhooks: WebhookConfig,
+    #[serde(default)]
+    pub membership_webhook: MembershipWebhookConfig,
+    #[serde(default)]
+    pub writeback: WritebackConfig,
+    #[serde(default)]
+    pub reverification: ReverificationConfig,
+    #[serde(default)]
+    pub grand_prix: GrandPrixConfig,
+    #[serde(default)]
+    pub api: ApiConfig,
+    #[serde(default)]
+    pub testing: TestingConfig,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -26,6 +59,12 @@ pub struct OrgConfig {
     pub password_placeholder: String,
     pub password_explanation: String,
     pub memberid_pattern: String,
+    #[serde(default = "default_locale")]
+    pub default_locale: String,
+}
+
+fn default_locale() -> String {
+    String::from("en")
 }
 
 #[derive(Deserialize)]
@@ -35,28 +74,707 @@ pub struct ExpiryConfig {
     pub membership_day: u32,
     pub renewal_month: u32,
     pub renewal_day: u32,
+    #[serde(default = "default_expiry_schedule")]
+    pub schedule: String,
+}
+
+fn default_expiry_schedule() -> String {
+    String::from("0 0 10 * * *")
 }
 
 #[derive(Deserialize)]
 pub struct ServerConfig {
     pub url: String,
-    pub expiry_check_interval_seconds: u64,
     pub postgres_options: String,
 }
 
-#[derive(Deserialize)]
+pub const PRODUCTION_LICHESS_DOMAIN: &str = "lichess.org";
+
+fn default_lichess_domain() -> String {
+    String::from(PRODUCTION_LICHESS_DOMAIN)
+}
+
+// Whether `domain` points at production Lichess however it is written, such as
+// `https://www.Lichess.org/` or `lichess.org:443`.
+fn is_production_lichess_domain(domain: &str) -> bool {
+    let domain = domain.trim().to_lowercase();
+    let host = domain
+        .strip_prefix("https://")
+        .or_else(|| domain.strip_prefix("http://"))
+        .unwrap_or(&domain);
+    let host = host.split(['/', '?', '#']).next().unwrap_or_default();
+    let host = host.rsplit_once(':').map_or(host, |(host, _)| host);
+    let host = host.trim_end_matches('.');
+    host.strip_prefix("www.").unwrap_or(host) == PRODUCTION_LICHESS_DOMAIN
+}
+
+#[derive(Deserialize, Clone)]
 pub struct LichessConfig {
+    #[serde(default = "default_lichess_domain")]
+    pub domain: String,
     pub client_id: String,
     pub team_admin: String,
     pub personal_api_token: String,
     pub team_password: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct AzolveConfig {
     pub api: String,
     pub api_pwd: String,
     pub api_token: String,
-    pub test_backdoor_member_id: String,
-    pub test_backdoor_password: String,
+    // Stored procedure for looking up a member's expiry by member ID; empty if there is none.
+    #[serde(default)]
+    pub lookup_object: String,
+    // Stored procedure for writing a member's Lichess ID back; empty to not write back.
+    #[serde(default)]
+    pub write_object: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct KickQueueConfig {
+    pub delay_ms: u64,
+    pub max_attempts: i32,
+    pub base_retry_seconds: i64,
+    pub poll_seconds: u64,
+}
+
+impl Default for KickQueueConfig {
+    fn default() -> Self {
+        KickQueueConfig {
+            delay_ms: 1000,
+            max_attempts: 8,
+            base_retry_seconds: 60,
+            poll_seconds: 60,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct LinkLimitConfig {
+    pub free_attempts: i32,
+    pub base_lockout_seconds: i64,
+    pub max_lockout_seconds: i64,
+    pub forget_after_seconds: i64,
+}
+
+impl Default for LinkLimitConfig {
+    fn default() -> Self {
+        LinkLimitConfig {
+            free_attempts: 3,
+            base_lockout_seconds: 60,
+            max_lockout_seconds: 86400,
+            forget_after_seconds: 86400,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct LeaderElectionConfig {
+    pub lease_seconds: i64,
+}
+
+impl Default for LeaderElectionConfig {
+    fn default() -> Self {
+        LeaderElectionConfig { lease_seconds: 30 }
+    }
+}
+
+// Periods are in days, 0 keeps records forever. Actions are "delete" or "anonymise".
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct RetentionConfig {
+    pub schedule: String,
+    pub salt: String,
+    pub referral_days: i64,
+    pub referral_action: String,
+    pub audit_days: i64,
+    pub audit_action: String,
+    pub membership_history_days: i64,
+    pub membership_history_action: String,
+    pub kick_queue_days: i64,
+    pub kick_queue_action: String,
+    pub webhook_queue_days: i64,
+    pub webhook_queue_action: String,
+    pub writeback_queue_days: i64,
+    pub writeback_queue_action: String,
+    pub tournament_results_days: i64,
+    pub tournament_results_action: String,
+    pub link_attempts_days: i64,
+    pub link_attempts_action: String,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            schedule: String::from("0 0 4 * * *"),
+            salt: String::new(),
+            referral_days: 0,
+            referral_action: String::from(retention::ANONYMISE),
+            audit_days: 0,
+            audit_action: String::from(retention::ANONYMISE),
+            membership_history_days: 0,
+            membership_history_action: String::from(retention::DELETE),
+            kick_queue_days: 0,
+            kick_queue_action: String::from(retention::DELETE),
+            webhook_queue_days: 0,
+            webhook_queue_action: String::from(retention::DELETE),
+            writeback_queue_days: 0,
+            writeback_queue_action: String::from(retention::DELETE),
+            tournament_results_days: 0,
+            tournament_results_action: String::from(retention::DELETE),
+            link_attempts_days: 0,
+            link_attempts_action: String::from(retention::DELETE),
+        }
+    }
+}
+
+// Membership events are POSTed to every URL, signed with `secret`.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct WebhookConfig {
+    pub urls: Vec<String>,
+    pub secret: String,
+    pub timeout_seconds: u64,
+    pub max_attempts: i32,
+    pub base_retry_seconds: i64,
+    pub poll_seconds: u64,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            urls: vec![],
+            secret: String::new(),
+            timeout_seconds: 10,
+            max_attempts: 10,
+            base_retry_seconds: 30,
+            poll_seconds: 10,
+        }
+    }
+}
+
+// Lets the membership system report renewals and lapses. Disabled while `secret` is empty.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct MembershipWebhookConfig {
+    pub secret: String,
+    pub max_age_seconds: i64,
+}
+
+impl Default for MembershipWebhookConfig {
+    fn default() -> Self {
+        MembershipWebhookConfig {
+            secret: String::new(),
+            max_age_seconds: 300,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct WritebackConfig {
+    pub delay_ms: u64,
+    pub max_attempts: i32,
+    pub base_retry_seconds: i64,
+    pub poll_seconds: u64,
+}
+
+impl Default for WritebackConfig {
+    fn default() -> Self {
+        WritebackConfig {
+            delay_ms: 500,
+            max_attempts: 8,
+            base_retry_seconds: 60,
+            poll_seconds: 30,
+        }
+    }
+}
+
+// Checks linked members with the membership backend before they expire, and extends the
+// memberships that have been renewed.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReverificationConfig {
+    pub enable: bool,
+    pub schedule: String,
+    pub delay_ms: u64,
+    pub window_days: i64,
+}
+
+impl Default for ReverificationConfig {
+    fn default() -> Self {
+        ReverificationConfig {
+            enable: false,
+            schedule: String::from("0 30 9 * * *"),
+            delay_ms: 1000,
+            window_days: 30,
+        }
+    }
+}
+
+// Season standings over a series of arenas, scored the same way for every series.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct GrandPrixConfig {
+    pub schedule: String,
+    pub points: Vec<i32>,
+    pub participation_points: i32,
+    pub rank_among_members: bool,
+    pub best_of: usize,
+    pub series: Vec<GrandPrixSeries>,
+}
+
+impl Default for GrandPrixConfig {
+    fn default() -> Self {
+        GrandPrixConfig {
+            schedule: String::from("0 15 * * * *"),
+            points: vec![25, 18, 15, 12, 10, 8, 6, 4, 2, 1],
+            participation_points: 0,
+            rank_among_members: true,
+            best_of: 0,
+            series: vec![],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct GrandPrixSeries {
+    pub name: String,
+    pub slug: String,
+    pub tournaments: Vec<String>,
+    pub discover: bool,
+    pub since: String,
+    pub until: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct ApiConfig {
+    pub max_lookup_ids: usize,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        ApiConfig {
+            max_lookup_ids: 300,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct TestingConfig {
+    pub enable: bool,
+    pub member_id: String,
+    pub password: String,
+}
+
+pub const DEFAULT_PATH: &str = "Config.toml";
+pub const PATH_VARIABLE: &str = "ORG2LICHESS_CONFIG";
+const OVERRIDE_PREFIX: &str = "ORG2LICHESS_";
+
+// Applies ORG2LICHESS_<SECTION>__<KEY> environment variables on top of the file.
+// ORG2LICHESS_<SECTION>__<KEY>_FILE reads the value from a file instead, for mounted secrets.
+fn apply_env_overrides(table: &mut Table) -> Result<(), ErrorBox> {
+    for (name, raw) in env::vars() {
+        let Some((section, key)) = name
+            .strip_prefix(OVERRIDE_PREFIX)
+            .and_then(|rest| rest.split_once("__"))
+        else {
+            continue;
+        };
+        let section = section.to_lowercase();
+        let key = key.to_lowercase();
+
+        let (key, raw) = match key.strip_suffix("_file") {
+            Some(key) => {
+                let contents = fs::read_to_string(&raw)
+                    .map_err(|e| format!("Cannot read {} ({}): {}", raw, name, e))?;
+                (
+                    key.to_string(),
+                    contents.trim_end_matches(['\r', '\n']).to_string(),
+                )
+            }
+            None => (key, raw),
+        };
+
+        let section_table = table
+            .entry(section.clone())
+            .or_insert_with(|| Value::Table(Table::new()))
+            .as_table_mut()
+            .ok_or(format!("{}: {} is not a section", name, section))?;
+        let value = match section_table.get(&key) {
+            Some(Value::Integer(_)) => Value::Integer(
+                raw.parse()
+                    .map_err(|e| format!("{}: expected an integer ({})", name, e))?,
+            ),
+            Some(Value::Boolean(_)) => Value::Boolean(
+                raw.parse()
+                    .map_err(|e| format!("{}: expected true or false ({})", name, e))?,
+            ),
+            // Keys missing from the file have no type to follow: flags are booleans, the rest strings.
+            None if raw == "true" || raw == "false" => Value::Boolean(raw == "true"),
+            _ => Value::String(raw),
+        };
+        section_table.insert(key, value);
+    }
+    Ok(())
+}
+
+// Writes the defaults of an optional section into the table, so that environment overrides
+// for keys missing from the file still get the right type.
+fn fill_defaults<T: Serialize>(
+    table: &mut Table,
+    section: &str,
+    defaults: &T,
+) -> Result<(), ErrorBox> {
+    let defaults = Table::try_from(defaults)?;
+    if let Some(section_table) = table
+        .entry(section)
+        .or_insert_with(|| Value::Table(Table::new()))
+        .as_table_mut()
+    {
+        for (key, value) in defaults {
+            section_table.entry(key).or_insert(value);
+        }
+    }
+    Ok(())
+}
+
+pub fn load(path: &str) -> Result<Config, ErrorBox> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+    let mut table: Table =
+        toml::from_str(&contents).map_err(|e| format!("Invalid {}: {}", path, e))?;
+    fill_defaults(&mut table, "kick_queue", &KickQueueConfig::default())?;
+    fill_defaults(&mut table, "link_limit", &LinkLimitConfig::default())?;
+    fill_defaults(
+        &mut table,
+        "leader_election",
+        &LeaderElectionConfig::default(),
+    )?;
+    fill_defaults(&mut table, "retention", &RetentionConfig::default())?;
+    fill_defaults(&mut table, "webhooks", &WebhookConfig::default())?;
+    fill_defaults(
+        &mut table,
+        "membership_webhook",
+        &MembershipWebhookConfig::default(),
+    )?;
+    fill_defaults(&mut table, "writeback", &WritebackConfig::default())?;
+    fill_defaults(
+        &mut table,
+        "reverification",
+        &ReverificationConfig::default(),
+    )?;
+    fill_defaults(&mut table, "grand_prix", &GrandPrixConfig::default())?;
+    fill_defaults(&mut table, "api", &ApiConfig::default())?;
+    fill_defaults(&mut table, "testing", &TestingConfig::default())?;
+    apply_env_overrides(&mut table)?;
+    let config: Config = table
+        .try_into()
+        .map_err(|e| format!("Invalid {}: {}", path, e))?;
+    let problems = validate(&config);
+    if !problems.is_empty() {
+        return Err(format!("Invalid {}:\n  - {}", path, problems.join("\n  - ")).into());
+    }
+    Ok(config)
+}
+
+// A non-leap year, so that February 29 is rejected: the dates have to exist every year.
+const VALIDATION_YEAR: i32 = 2023;
+
+fn check_date(problems: &mut Vec<String>, name: &str, month: u32, day: u32) -> bool {
+    if NaiveDate::from_ymd_opt(VALIDATION_YEAR, month, day).is_none() {
+        problems.push(format!(
+            "expiry.{}_month/{}_day: {}/{} is not a valid date in every year",
+            name, name, month, day
+        ));
+        return false;
+    }
+    true
+}
+
+fn check_url(problems: &mut Vec<String>, name: &str, value: &str) {
+    match Url::parse(value) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {}
+        Ok(_) => problems.push(format!("{}: {} is not an http(s) URL", name, value)),
+        Err(e) => problems.push(format!("{}: {} is not a valid URL ({})", name, value, e)),
+    }
+}
+
+pub fn validate(config: &Config) -> Vec<String> {
+    let mut problems = vec![];
+
+    if let Err(e) = org::timezone_from_string(&config.org.timezone) {
+        problems.push(format!("org.timezone: {} ({})", config.org.timezone, e));
+    }
+
+    if let Err(e) = Regex::new(&format!("^(?:{})$", config.org.memberid_pattern)) {
+        problems.push(format!(
+            "org.memberid_pattern: invalid regular expression ({})",
+            e
+        ));
+    }
+
+    let expiry = &config.expiry;
+    let membership_valid = check_date(
+        &mut problems,
+        "membership",
+        expiry.membership_month,
+        expiry.membership_day,
+    );
+    let renewal_valid = check_date(
+        &mut problems,
+        "renewal",
+        expiry.renewal_month,
+        expiry.renewal_day,
+    );
+    if membership_valid
+        && renewal_valid
+        && (expiry.renewal_month, expiry.renewal_day)
+            <= (expiry.membership_month, expiry.membership_day)
+    {
+        problems.push(format!(
+            "expiry: renewal date {}/{} must come after membership expiry {}/{} in the same year",
+            expiry.renewal_month,
+            expiry.renewal_day,
+            expiry.membership_month,
+            expiry.membership_day
+        ));
+    }
+
+    if let Err(e) = schedule::parse(&expiry.schedule) {
+        problems.push(format!("expiry.schedule: {}", e));
+    }
+
+    check_url(&mut problems, "server.url", &config.server.url);
+    if config.server.url.ends_with('/') {
+        problems.push(String::from("server.url: must not end with a slash"));
+    }
+    check_url(&mut problems, "org.icon", &config.org.icon);
+    check_url(&mut problems, "org.image", &config.org.image);
+    check_url(
+        &mut problems,
+        "org.referral_link",
+        &config.org.referral_link,
+    );
+    check_url(
+        &mut problems,
+        "org.authentication_secret_help_link",
+        &config.org.authentication_secret_help_link,
+    );
+    check_url(&mut problems, "azolve.api", &config.azolve.api);
+
+    let kick_queue = &config.kick_queue;
+    if kick_queue.max_attempts < 1
+        || kick_queue.base_retry_seconds < 1
+        || kick_queue.poll_seconds == 0
+    {
+        problems.push(String::from(
+            "kick_queue: max_attempts, base_retry_seconds and poll_seconds must be positive",
+        ));
+    }
+
+    let link_limit = &config.link_limit;
+    if link_limit.free_attempts < 1 {
+        problems.push(String::from("link_limit.free_attempts: must be at least 1"));
+    }
+    if link_limit.base_lockout_seconds < 1
+        || link_limit.max_lockout_seconds < link_limit.base_lockout_seconds
+    {
+        problems.push(String::from(
+            "link_limit: base_lockout_seconds must be positive and at most max_lockout_seconds",
+        ));
+    }
+
+    if config.leader_election.lease_seconds < 3 {
+        problems.push(String::from(
+            "leader_election.lease_seconds: must be at least 3",
+        ));
+    }
+
+    let retention = &config.retention;
+    if let Err(e) = schedule::parse(&retention.schedule) {
+        problems.push(format!("retention.schedule: {}", e));
+    }
+    for policy in retention::policies(retention) {
+        if policy.days < 0 {
+            problems.push(format!(
+                "retention.{}_days: must not be negative",
+                policy.name
+            ));
+        }
+        if !retention::is_valid_action(policy.action) {
+            problems.push(format!(
+                "retention.{}_action: must be \"{}\" or \"{}\"",
+                policy.name,
+                retention::DELETE,
+                retention::ANONYMISE
+            ));
+        }
+    }
+    if retention.salt.is_empty()
+        && retention::policies(retention)
+            .iter()
+            .any(|policy| policy.days > 0 && policy.action == retention::ANONYMISE)
+    {
+        problems.push(String::from(
+            "retention.salt: must be set when records are anonymised",
+        ));
+    }
+
+    let webhooks = &config.webhooks;
+    for url in &webhooks.urls {
+        check_url(&mut problems, "webhooks.urls", url);
+    }
+    if !webhooks.urls.is_empty() && webhooks.secret.is_empty() {
+        problems.push(String::from(
+            "webhooks.secret: must be set when webhook URLs are configured",
+        ));
+    }
+    if webhooks.timeout_seconds == 0
+        || webhooks.max_attempts < 1
+        || webhooks.base_retry_seconds < 1
+        || webhooks.poll_seconds == 0
+    {
+        problems.push(String::from(
+            "webhooks: timeout_seconds, max_attempts, base_retry_seconds and poll_seconds must be positive",
+        ));
+    }
+
+    if config.membership_webhook.max_age_seconds < 1 {
+        problems.push(String::from(
+            "membership_webhook.max_age_seconds: must be positive",
+        ));
+    }
+
+    let writeback = &config.writeback;
+    if writeback.max_attempts < 1 || writeback.base_retry_seconds < 1 || writeback.poll_seconds == 0
+    {
+        problems.push(String::from(
+            "writeback: max_attempts, base_retry_seconds and poll_seconds must be positive",
+        ));
+    }
+
+    let reverification = &config.reverification;
+    if let Err(e) = schedule::parse(&reverification.schedule) {
+        problems.push(format!("reverification.schedule: {}", e));
+    }
+    if reverification.window_days < 0 {
+        problems.push(String::from(
+            "reverification.window_days: must not be negative",
+        ));
+    }
+    if reverification.enable && !verifier::can_look_up(&config.azolve, &config.testing) {
+        problems.push(String::from(
+            "reverification.enable: azolve.lookup_object must be set to look members up",
+        ));
+    }
+
+    let grand_prix = &config.grand_prix;
+    if let Err(e) = schedule::parse(&grand_prix.schedule) {
+        problems.push(format!("grand_prix.schedule: {}", e));
+    }
+    if grand_prix.points.iter().any(|points| *points < 0) || grand_prix.participation_points < 0 {
+        problems.push(String::from(
+            "grand_prix: points and participation_points cannot be negative",
+        ));
+    }
+    for (i, series) in grand_prix.series.iter().enumerate() {
+        if series.name.is_empty() {
+            problems.push(format!("grand_prix.series[{}].name: must be set", i));
+        }
+        if series.slug.is_empty()
+            || !series
+                .slug
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        {
+            problems.push(format!(
+                "grand_prix.series[{}].slug: must be lowercase letters, digits and dashes",
+                i
+            ));
+        }
+        if grand_prix.series[..i]
+            .iter()
+            .any(|other| other.slug == series.slug)
+        {
+            problems.push(format!(
+                "grand_prix.series[{}].slug: {} is used more than once",
+                i, &series.slug
+            ));
+        }
+        if series.tournaments.is_empty() && !series.discover {
+            problems.push(format!(
+                "grand_prix.series[{}]: needs tournaments, or discover = true",
+                i
+            ));
+        }
+        for (key, date) in [("since", &series.since), ("until", &series.until)] {
+            if !date.is_empty() && NaiveDate::parse_from_str(date, "%Y-%m-%d").is_err() {
+                problems.push(format!(
+                    "grand_prix.series[{}].{}: {} is not a YYYY-MM-DD date",
+                    i, key, date
+                ));
+            }
+        }
+    }
+
+    if config.api.max_lookup_ids == 0 {
+        problems.push(String::from("api.max_lookup_ids: must be positive"));
+    }
+
+    if config.testing.enable {
+        if is_production_lichess_domain(&config.lichess.domain) {
+            problems.push(format!(
+                "testing.enable: test mode cannot be used with the production Lichess domain {}",
+                PRODUCTION_LICHESS_DOMAIN
+            ));
+        }
+        if config.testing.member_id.is_empty() || config.testing.password.is_empty() {
+            problems.push(String::from(
+                "testing: member_id and password must be set when test mode is enabled",
+            ));
+        }
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn production_lichess_is_recognised_however_it_is_written() {
+        for domain in [
+            "lichess.org",
+            "Lichess.org",
+            "https://lichess.org",
+            "http://lichess.org/",
+            "www.lichess.org",
+            "https://WWW.lichess.org/",
+            "lichess.org:443",
+            "lichess.org.",
+            " lichess.org ",
+        ] {
+            assert!(is_production_lichess_domain(domain), "{}", domain);
+        }
+    }
+
+    #[test]
+    fn other_lichess_instances_are_not_production() {
+        for domain in [
+            "localhost:9663",
+            "lichess.dev",
+            "notlichess.org",
+            "lichess.org.example",
+        ] {
+            assert!(!is_production_lichess_domain(domain), "{}", domain);
+        }
+    }
 }
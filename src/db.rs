@@ -1,58 +1,426 @@
+use crate::linklimit;
+use crate::retention::Target;
 use crate::types::*;
 use bb8::{Pool, PooledConnection};
 use bb8_postgres::PostgresConnectionManager;
-use postgres::NoTls;
-use serde::Serialize;
+use chrono::{DateTime, Utc};
+use postgres::error::SqlState;
+use postgres::types::ToSql;
+use postgres::{IsolationLevel, NoTls};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct Membership {
     pub org_id: String,
     pub lichess_id: String,
     pub exp_year: i32,
 }
 
+pub enum Registration {
+    Registered,
+    // Another registration of the same organization ID or Lichess account happened concurrently.
+    AlreadyLinked,
+    // `before_commit` returned false, so nothing was written.
+    Cancelled,
+}
+
+#[derive(Serialize)]
+pub struct LinkLockout {
+    pub kind: String,
+    pub subject: String,
+    pub failures: i32,
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct ReferralClick {
+    pub source: String,
+    pub clicked: DateTime<Utc>,
+}
+
+// Referral clicks in one month or from one source, and how many of the clicking users went on to
+// link a membership.
+#[derive(Serialize)]
+pub struct ReferralStat {
+    pub label: String,
+    pub clicks: i64,
+    pub users: i64,
+    pub conversions: i64,
+}
+
+#[derive(Serialize)]
+pub struct AuditEvent {
+    pub at: DateTime<Utc>,
+    pub event: String,
+    pub org_id: Option<String>,
+    pub details: String,
+}
+
+#[derive(Serialize)]
+pub struct QueuedKick {
+    pub id: i32,
+    pub lichess_id: String,
+    pub org_id: String,
+    pub exp_year: i32,
+    pub attempts: i32,
+    pub last_error: String,
+    pub next_attempt: DateTime<Utc>,
+}
+
+// An event waiting to be delivered to one webhook endpoint. `payload` is the exact JSON body that is
+// signed and sent.
+pub struct QueuedWebhook {
+    pub id: i32,
+    pub url: String,
+    pub event: String,
+    pub payload: String,
+    pub attempts: i32,
+}
+
+// A webhook delivery about a member, as included in their personal data export.
+#[derive(Serialize)]
+pub struct WebhookDelivery {
+    pub url: String,
+    pub event: String,
+    pub payload: String,
+    pub state: String,
+    pub created: DateTime<Utc>,
+}
+
+// A finished tournament whose results have been cached for the Grand Prix.
+#[derive(Serialize)]
+pub struct CachedTournament {
+    pub id: String,
+    pub name: String,
+    pub starts: DateTime<Utc>,
+}
+
+pub struct TournamentResultRow {
+    pub lichess_id: String,
+    pub rank: i32,
+    pub score: f64,
+}
+
+// The rank of a linked member in a cached tournament.
+pub struct LinkedResult {
+    pub tournament_id: String,
+    pub lichess_id: String,
+    pub rank: i32,
+}
+
+// A member's cached result in one tournament, as included in their personal data export.
+#[derive(Serialize)]
+pub struct TournamentResultRecord {
+    pub tournament_id: String,
+    pub name: String,
+    pub starts: DateTime<Utc>,
+    pub rank: i32,
+    pub score: f64,
+}
+
+// Only the hash of an API key is stored; `prefix` is the start of the key, to tell keys apart.
+#[derive(Serialize)]
+pub struct ApiKeyRecord {
+    pub id: i32,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<String>,
+    pub created: DateTime<Utc>,
+    pub created_by: String,
+    pub last_used: Option<DateTime<Utc>>,
+    pub revoked: Option<DateTime<Utc>>,
+}
+
+// A Lichess ID waiting to be written to the membership backend; empty to clear it.
+pub struct QueuedWriteback {
+    pub id: i32,
+    pub org_id: String,
+    pub lichess_id: String,
+    pub attempts: i32,
+}
+
+// A write-back of a member's Lichess ID, as included in their personal data export.
+#[derive(Serialize)]
+pub struct WritebackRecord {
+    pub org_id: String,
+    pub lichess_id: String,
+    pub state: String,
+    pub created: DateTime<Utc>,
+}
+
+pub const WRITEBACK_DONE: &str = "done";
+pub const WRITEBACK_FAILED: &str = "failed";
+
+pub const WEBHOOK_DONE: &str = "done";
+pub const WEBHOOK_FAILED: &str = "failed";
+
+pub const KICK_DONE: &str = "done";
+pub const KICK_CANCELLED: &str = "cancelled";
+pub const KICK_FAILED: &str = "failed";
+
+#[derive(Serialize)]
+pub struct PastMembership {
+    pub org_id: String,
+    pub lichess_id: String,
+    pub exp_year: i32,
+    pub state: String,
+    pub ended: DateTime<Utc>,
+}
+
+pub const ENDED_KICKED: &str = "kicked";
+pub const ENDED_UNLINKED: &str = "unlinked";
+pub const ENDED_TRANSFERRED: &str = "transferred";
+
 type DbPool = Pool<PostgresConnectionManager<NoTls>>;
 type DbConnection<'a> = PooledConnection<'a, PostgresConnectionManager<NoTls>>;
 
 #[derive(Clone)]
 pub struct OrgDbClient(DbPool);
 
+// A Postgres advisory lock held on its own connection until released.
+pub struct JobLock {
+    connection: DbConnection<'static>,
+    name: String,
+}
+
+impl JobLock {
+    pub async fn release(self) -> Result<(), ErrorBox> {
+        self.connection
+            .execute("SELECT pg_advisory_unlock(hashtext($1))", &[&self.name])
+            .await?;
+        Ok(())
+    }
+}
+
+const SCHEMA: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS memberships (orgid varchar not null primary key, lichessid varchar not null unique, exp integer not null)",
+    "CREATE TABLE IF NOT EXISTS ref (lichessid varchar not null primary key)",
+    "ALTER TABLE ref ADD COLUMN IF NOT EXISTS clicked timestamptz not null default now()",
+    "ALTER TABLE ref ADD COLUMN IF NOT EXISTS converted timestamptz",
+    "CREATE TABLE IF NOT EXISTS referral_clicks (id serial primary key, lichessid varchar not null, source varchar not null default '', clicked timestamptz not null default now())",
+    "ALTER TABLE referral_clicks ALTER COLUMN lichessid DROP NOT NULL",
+    "ALTER TABLE referral_clicks ADD COLUMN IF NOT EXISTS visitor varchar",
+    "CREATE INDEX IF NOT EXISTS referral_clicks_visitor ON referral_clicks (visitor) WHERE lichessid IS NULL",
+    "CREATE TABLE IF NOT EXISTS audit (id serial primary key, at timestamptz not null default now(), event varchar not null, lichessid varchar, orgid varchar, details varchar not null default '')",
+    "CREATE TABLE IF NOT EXISTS link_attempts (kind varchar not null, subject varchar not null, failures integer not null, last_failure timestamptz not null, locked_until timestamptz, primary key (kind, subject))",
+    "CREATE TABLE IF NOT EXISTS membership_history (id serial primary key, orgid varchar not null, lichessid varchar not null, exp integer not null, state varchar not null, ended timestamptz not null default now())",
+    "CREATE TABLE IF NOT EXISTS kick_queue (id serial primary key, lichessid varchar not null, orgid varchar not null, exp integer not null, state varchar not null default 'pending', attempts integer not null default 0, next_attempt timestamptz not null default now(), last_error varchar not null default '', created timestamptz not null default now())",
+    "CREATE UNIQUE INDEX IF NOT EXISTS kick_queue_pending ON kick_queue (lichessid) WHERE state = 'pending'",
+    "CREATE TABLE IF NOT EXISTS webhook_queue (id serial primary key, url varchar not null, event varchar not null, lichessid varchar not null, payload text not null, state varchar not null default 'pending', attempts integer not null default 0, next_attempt timestamptz not null default now(), last_error varchar not null default '', created timestamptz not null default now())",
+    "CREATE INDEX IF NOT EXISTS webhook_queue_due ON webhook_queue (next_attempt) WHERE state = 'pending'",
+    "CREATE TABLE IF NOT EXISTS writeback_queue (id serial primary key, orgid varchar not null, lichessid varchar not null, state varchar not null default 'pending', attempts integer not null default 0, next_attempt timestamptz not null default now(), last_error varchar not null default '', created timestamptz not null default now())",
+    "CREATE UNIQUE INDEX IF NOT EXISTS writeback_queue_pending ON writeback_queue (orgid) WHERE state = 'pending'",
+    "CREATE TABLE IF NOT EXISTS api_keys (id serial primary key, name varchar not null, prefix varchar not null, hash varchar not null unique, scopes varchar not null, created timestamptz not null default now(), created_by varchar not null, last_used timestamptz, revoked timestamptz)",
+    "CREATE TABLE IF NOT EXISTS tournaments (id varchar not null primary key, name varchar not null, starts timestamptz not null, teamid varchar not null default '', fetched timestamptz not null default now())",
+    "CREATE TABLE IF NOT EXISTS tournament_results (tournamentid varchar not null, lichessid varchar not null, rank integer not null, score double precision not null, primary key (tournamentid, lichessid))",
+    "CREATE TABLE IF NOT EXISTS job_runs (name varchar not null primary key, last_run timestamptz not null)",
+    "CREATE TABLE IF NOT EXISTS leases (name varchar not null primary key, holder varchar not null, expires timestamptz not null)",
+];
+
 pub async fn connect(connection_options: &str) -> Result<OrgDbClient, ErrorBox> {
     let manager = PostgresConnectionManager::new(connection_options.parse()?, NoTls);
     let pool = Pool::builder().max_size(10).build(manager).await?;
     Ok(OrgDbClient(pool))
 }
 
+fn extract_queued_kicks(rows: &[postgres::row::Row]) -> Vec<QueuedKick> {
+    rows.iter()
+        .map(|row| QueuedKick {
+            id: row.get(0),
+            lichess_id: row.get(1),
+            org_id: row.get(2),
+            exp_year: row.get(3),
+            attempts: row.get(4),
+            last_error: row.get(5),
+            next_attempt: row.get(6),
+        })
+        .collect()
+}
+
+fn extract_api_keys(rows: &[postgres::row::Row]) -> Vec<ApiKeyRecord> {
+    rows.iter()
+        .map(|row| ApiKeyRecord {
+            id: row.get(0),
+            name: row.get(1),
+            prefix: row.get(2),
+            scopes: row
+                .get::<_, String>(3)
+                .split_whitespace()
+                .map(String::from)
+                .collect(),
+            created: row.get(4),
+            created_by: row.get(5),
+            last_used: row.get(6),
+            revoked: row.get(7),
+        })
+        .collect()
+}
+
+fn extract_past_memberships(rows: &[postgres::row::Row]) -> Vec<PastMembership> {
+    rows.iter()
+        .map(|row| PastMembership {
+            org_id: row.get(0),
+            lichess_id: row.get(1),
+            exp_year: row.get(2),
+            state: row.get(3),
+            ended: row.get(4),
+        })
+        .collect()
+}
+
 fn extract_one_membership(rows: &[postgres::row::Row]) -> Option<Membership> {
-    rows.get(0).map(|row| Membership {
+    rows.first().map(|row| Membership {
         org_id: row.get(0),
         lichess_id: row.get(1),
         exp_year: row.get(2),
     })
 }
 
+// A unique violation or serialization failure means another transaction linked the same
+// organization ID or Lichess account first.
+fn is_conflict(e: &postgres::Error) -> bool {
+    matches!(
+        e.code(),
+        Some(&SqlState::UNIQUE_VIOLATION | &SqlState::T_R_SERIALIZATION_FAILURE)
+    )
+}
+
 impl OrgDbClient {
     async fn w(&self) -> Result<DbConnection<'_>, ErrorBox> {
         Ok(self.0.get().await?)
     }
 
+    pub async fn migrate(&self) -> Result<(), ErrorBox> {
+        let client = self.w().await?;
+        for statement in SCHEMA {
+            client.batch_execute(statement).await?;
+        }
+        Ok(())
+    }
+
+    // Links `org_id` to `lichess_id`, replacing any link either of them had.
     pub async fn register_member(
         &self,
         org_id: &str,
         lichess_id: &str,
         exp_year: i32,
-    ) -> Result<u64, ErrorBox> {
-        let client = self.w().await?;
-        client
+    ) -> Result<Registration, ErrorBox> {
+        self.register(org_id, lichess_id, exp_year, true, async { true })
+            .await
+    }
+
+    // Links `org_id` to `lichess_id`, replacing any link the Lichess account had. An organization
+    // ID that is linked to another account is left alone and makes this return `AlreadyLinked`.
+    // `before_commit` runs while the transaction is still open, so if it returns false nothing is
+    // written.
+    pub async fn register_member_with<F>(
+        &self,
+        org_id: &str,
+        lichess_id: &str,
+        exp_year: i32,
+        before_commit: F,
+    ) -> Result<Registration, ErrorBox>
+    where
+        F: Future<Output = bool>,
+    {
+        self.register(org_id, lichess_id, exp_year, false, before_commit)
+            .await
+    }
+
+    // Both registrations run in one serializable transaction, so a concurrent registration of the
+    // same organization ID or Lichess account makes one of them return `AlreadyLinked` instead of
+    // failing.
+    async fn register<F>(
+        &self,
+        org_id: &str,
+        lichess_id: &str,
+        exp_year: i32,
+        replace_org_link: bool,
+        before_commit: F,
+    ) -> Result<Registration, ErrorBox>
+    where
+        F: Future<Output = bool>,
+    {
+        let mut client = self.w().await?;
+        let transaction = client
+            .build_transaction()
+            .isolation_level(IsolationLevel::Serializable)
+            .start()
+            .await?;
+        let result = async {
+            if !replace_org_link
+                && !transaction
+                    .query(
+                        "SELECT 1 FROM memberships WHERE orgid = $1 AND lichessid <> $2",
+                        &[&org_id, &lichess_id],
+                    )
+                    .await?
+                    .is_empty()
+            {
+                return Ok(false);
+            }
+            transaction
+                .execute(
+                    "INSERT INTO membership_history (orgid, lichessid, exp, state) \
+                    SELECT orgid, lichessid, exp, $3 FROM memberships \
+                    WHERE (orgid = $1 OR lichessid = $2) AND NOT (orgid = $1 AND lichessid = $2)",
+                    &[&org_id, &lichess_id, &ENDED_UNLINKED],
+                )
+                .await?;
+            transaction
+                .execute(
+                    "DELETE FROM memberships WHERE orgid = $1 OR lichessid = $2",
+                    &[&org_id, &lichess_id],
+                )
+                .await?;
+            transaction
+                .execute(
+                    "INSERT INTO memberships (orgid, lichessid, exp) VALUES ($1, $2, $3)",
+                    &[&org_id, &lichess_id, &exp_year],
+                )
+                .await?;
+            Ok(true)
+        }
+        .await;
+
+        match result {
+            Ok(true) => {}
+            Ok(false) => return Ok(Registration::AlreadyLinked),
+            Err(e) if is_conflict(&e) => return Ok(Registration::AlreadyLinked),
+            Err(e) => return Err(e.into()),
+        }
+        if !before_commit.await {
+            return Ok(Registration::Cancelled);
+        }
+        match transaction.commit().await {
+            Ok(()) => Ok(Registration::Registered),
+            Err(e) if is_conflict(&e) => Ok(Registration::AlreadyLinked),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub async fn set_expiry_year(&self, org_id: &str, exp_year: i32) -> Result<u64, ErrorBox> {
+        let result = self
+            .w()
+            .await?
             .execute(
-                "DELETE FROM memberships WHERE orgid = $1 OR lichessid = $2",
-                &[&org_id, &lichess_id],
+                "UPDATE memberships SET exp = $2 WHERE orgid = $1",
+                &[&org_id, &exp_year],
             )
             .await?;
-        let result = client
+        Ok(result)
+    }
+
+    // Only ever moves the expiry year later, so a stale update cannot shorten a membership. Returns
+    // 0 when the membership already expires in `exp_year` or later.
+    pub async fn extend_expiry_year(&self, org_id: &str, exp_year: i32) -> Result<u64, ErrorBox> {
+        let result = self
+            .w()
+            .await?
             .execute(
-                "INSERT INTO memberships (orgid, lichessid, exp) VALUES ($1, $2, $3)",
-                &[&org_id, &lichess_id, &exp_year],
+                "UPDATE memberships SET exp = $2 WHERE orgid = $1 AND exp < $2",
+                &[&org_id, &exp_year],
             )
             .await?;
         Ok(result)
@@ -88,25 +456,171 @@ impl OrgDbClient {
         Ok(extract_one_membership(&rows))
     }
 
-    pub async fn remove_membership(&self, org_id: &str) -> Result<u64, ErrorBox> {
-        let result = self
+    // Moves the membership of `org_id` from one Lichess account to another in one transaction, and
+    // returns 0 if it was no longer linked to `from`. A membership `to` had before is ended.
+    // Like `register_member_with`, `before_commit` runs once the transfer is written, and nothing is
+    // kept unless it returns true.
+    pub async fn transfer_membership<F>(
+        &self,
+        org_id: &str,
+        from: &str,
+        to: &str,
+        exp_year: i32,
+        before_commit: F,
+    ) -> Result<Registration, ErrorBox>
+    where
+        F: Future<Output = bool>,
+    {
+        let mut client = self.w().await?;
+        let transaction = client.transaction().await?;
+        transaction
+            .execute(
+                "INSERT INTO membership_history (orgid, lichessid, exp, state) \
+                SELECT orgid, lichessid, exp, $2 FROM memberships WHERE lichessid = $1",
+                &[&to, &ENDED_UNLINKED],
+            )
+            .await?;
+        transaction
+            .execute("DELETE FROM memberships WHERE lichessid = $1", &[&to])
+            .await?;
+        transaction
+            .execute(
+                "INSERT INTO membership_history (orgid, lichessid, exp, state) \
+                SELECT orgid, lichessid, exp, $3 FROM memberships WHERE orgid = $1 AND lichessid = $2",
+                &[&org_id, &from, &ENDED_TRANSFERRED],
+            )
+            .await?;
+        let result = transaction
+            .execute(
+                "UPDATE memberships SET lichessid = $3, exp = $4 WHERE orgid = $1 AND lichessid = $2",
+                &[&org_id, &from, &to, &exp_year],
+            )
+            .await?;
+        if result == 0 {
+            return Ok(Registration::AlreadyLinked);
+        }
+        if !before_commit.await {
+            return Ok(Registration::Cancelled);
+        }
+        transaction.commit().await?;
+        Ok(Registration::Registered)
+    }
+
+    // Moves the matching membership into membership_history, marked as kicked or unlinked.
+    async fn end_membership_where(
+        &self,
+        column: &str,
+        value: &str,
+        state: &str,
+    ) -> Result<u64, ErrorBox> {
+        let mut client = self.w().await?;
+        let transaction = client.transaction().await?;
+        transaction
+            .execute(
+                &format!(
+                    "INSERT INTO membership_history (orgid, lichessid, exp, state) \
+                    SELECT orgid, lichessid, exp, $2 FROM memberships WHERE {} = $1",
+                    column
+                ),
+                &[&value, &state],
+            )
+            .await?;
+        let result = transaction
+            .execute(
+                &format!("DELETE FROM memberships WHERE {} = $1", column),
+                &[&value],
+            )
+            .await?;
+        transaction.commit().await?;
+        Ok(result)
+    }
+
+    pub async fn end_membership(&self, org_id: &str, state: &str) -> Result<u64, ErrorBox> {
+        self.end_membership_where("orgid", org_id, state).await
+    }
+
+    pub async fn end_membership_by_lichess_id(
+        &self,
+        lichess_id: &str,
+        state: &str,
+    ) -> Result<u64, ErrorBox> {
+        self.end_membership_where("lichessid", lichess_id, state)
+            .await
+    }
+
+    // Deletes everything stored about a Lichess account apart from the audit log.
+    pub async fn erase_member(&self, lichess_id: &str) -> Result<Option<Membership>, ErrorBox> {
+        let mut client = self.w().await?;
+        let transaction = client.transaction().await?;
+        let rows = transaction
+            .query(
+                "DELETE FROM memberships WHERE lichessid = $1 RETURNING orgid, lichessid, exp",
+                &[&lichess_id],
+            )
+            .await?;
+        for statement in [
+            "DELETE FROM membership_history WHERE lichessid = $1",
+            "DELETE FROM kick_queue WHERE lichessid = $1",
+            "DELETE FROM webhook_queue WHERE lichessid = $1",
+            "DELETE FROM writeback_queue WHERE lichessid = $1",
+            "DELETE FROM tournament_results WHERE lichessid = $1",
+            "DELETE FROM ref WHERE lichessid = $1",
+            "DELETE FROM referral_clicks WHERE lichessid = $1",
+        ] {
+            transaction.execute(statement, &[&lichess_id]).await?;
+        }
+        transaction
+            .execute(
+                "DELETE FROM link_attempts WHERE kind = $1 AND subject = $2",
+                &[&linklimit::LICHESS_ACCOUNT, &lichess_id],
+            )
+            .await?;
+        transaction.commit().await?;
+        Ok(extract_one_membership(&rows))
+    }
+
+    pub async fn get_membership_history(&self) -> Result<Vec<PastMembership>, ErrorBox> {
+        let rows = self
+            .w()
+            .await?
+            .query(
+                "SELECT orgid, lichessid, exp, state, ended FROM membership_history ORDER BY ended DESC",
+                &[],
+            )
+            .await?;
+        Ok(extract_past_memberships(&rows))
+    }
+
+    pub async fn get_membership_history_for_lichess_id(
+        &self,
+        lichess_id: &str,
+    ) -> Result<Vec<PastMembership>, ErrorBox> {
+        let rows = self
             .w()
             .await?
-            .execute("DELETE FROM memberships WHERE orgid = $1", &[&org_id])
+            .query(
+                "SELECT orgid, lichessid, exp, state, ended FROM membership_history \
+                WHERE lichessid = $1 ORDER BY ended DESC",
+                &[&lichess_id],
+            )
             .await?;
-        Ok(result)
+        Ok(extract_past_memberships(&rows))
     }
 
-    pub async fn remove_membership_by_lichess_id(&self, lichess_id: &str) -> Result<u64, ErrorBox> {
-        let result = self
+    pub async fn get_last_past_membership_for_lichess_id(
+        &self,
+        lichess_id: &str,
+    ) -> Result<Option<PastMembership>, ErrorBox> {
+        let rows = self
             .w()
             .await?
-            .execute(
-                "DELETE FROM memberships WHERE lichessid = $1",
+            .query(
+                "SELECT orgid, lichessid, exp, state, ended FROM membership_history \
+                WHERE lichessid = $1 ORDER BY ended DESC LIMIT 1",
                 &[&lichess_id],
             )
             .await?;
-        Ok(result)
+        Ok(extract_past_memberships(&rows).pop())
     }
 
     pub async fn get_members(&self) -> Result<Vec<Membership>, ErrorBox> {
@@ -126,6 +640,30 @@ impl OrgDbClient {
         Ok(members)
     }
 
+    // Looks up many Lichess accounts at once; accounts that are not linked are left out.
+    pub async fn get_members_for_lichess_ids(
+        &self,
+        lichess_ids: &[String],
+    ) -> Result<Vec<Membership>, ErrorBox> {
+        let mut members: Vec<Membership> = vec![];
+        for row in self
+            .w()
+            .await?
+            .query(
+                "SELECT orgid, lichessid, exp FROM memberships WHERE lichessid = ANY($1)",
+                &[&lichess_ids],
+            )
+            .await?
+        {
+            members.push(Membership {
+                org_id: row.get(0),
+                lichess_id: row.get(1),
+                exp_year: row.get(2),
+            });
+        }
+        Ok(members)
+    }
+
     pub async fn get_members_with_at_most_expiry_year(
         &self,
         year: i32,
@@ -149,6 +687,42 @@ impl OrgDbClient {
         Ok(members)
     }
 
+    // Adds the memberships and referrals of an export in one transaction, so that a failure leaves
+    // nothing behind. Memberships whose organization ID or Lichess account is already linked are
+    // left as they are; their organization IDs are returned.
+    pub async fn import(
+        &self,
+        memberships: &[Membership],
+        referrals: &[String],
+    ) -> Result<Vec<String>, ErrorBox> {
+        let mut client = self.w().await?;
+        let transaction = client.transaction().await?;
+        let mut skipped = vec![];
+        for member in memberships {
+            if transaction
+                .execute(
+                    "INSERT INTO memberships (orgid, lichessid, exp) VALUES ($1, $2, $3) \
+                    ON CONFLICT DO NOTHING",
+                    &[&member.org_id, &member.lichess_id, &member.exp_year],
+                )
+                .await?
+                == 0
+            {
+                skipped.push(member.org_id.clone());
+            }
+        }
+        for lichess_id in referrals {
+            transaction
+                .execute(
+                    "INSERT INTO ref (lichessid) VALUES ($1) ON CONFLICT DO NOTHING",
+                    &[lichess_id],
+                )
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(skipped)
+    }
+
     pub async fn referral_click(&self, lichess_id: &str) -> Result<u64, ErrorBox> {
         let result = self
             .w()
@@ -161,12 +735,960 @@ impl OrgDbClient {
         Ok(result)
     }
 
-    pub async fn referral_count(&self) -> Result<i64, ErrorBox> {
-        let rows = self
+    pub async fn record_referral_click(
+        &self,
+        lichess_id: &str,
+        source: &str,
+    ) -> Result<(), ErrorBox> {
+        self.referral_click(lichess_id).await?;
+        self.w()
+            .await?
+            .execute(
+                "INSERT INTO referral_clicks (lichessid, source) VALUES ($1, $2)",
+                &[&lichess_id, &source],
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn record_anonymous_referral_click(
+        &self,
+        visitor: &str,
+        source: &str,
+    ) -> Result<(), ErrorBox> {
+        self.w()
+            .await?
+            .execute(
+                "INSERT INTO referral_clicks (visitor, source) VALUES ($1, $2)",
+                &[&visitor, &source],
+            )
+            .await?;
+        Ok(())
+    }
+
+    // Attributes the clicks of an anonymous visitor to the Lichess account they logged in with.
+    pub async fn claim_anonymous_referrals(
+        &self,
+        visitor: &str,
+        lichess_id: &str,
+    ) -> Result<u64, ErrorBox> {
+        let claimed = self
             .w()
             .await?
-            .query("SELECT COUNT(*) FROM ref", &[])
+            .execute(
+                "UPDATE referral_clicks SET lichessid = $2 WHERE visitor = $1 AND lichessid IS NULL",
+                &[&visitor, &lichess_id],
+            )
+            .await?;
+        if claimed > 0 {
+            self.referral_click(lichess_id).await?;
+        }
+        Ok(claimed)
+    }
+
+    pub async fn mark_referral_converted(&self, lichess_id: &str) -> Result<u64, ErrorBox> {
+        let result = self
+            .w()
+            .await?
+            .execute(
+                "UPDATE ref SET converted = now() WHERE lichessid = $1 AND converted IS NULL",
+                &[&lichess_id],
+            )
             .await?;
-        Ok(rows.get(0).ok_or("no row returned")?.get(0))
+        Ok(result)
+    }
+
+    pub async fn get_referral_clicks_for_lichess_id(
+        &self,
+        lichess_id: &str,
+    ) -> Result<Vec<ReferralClick>, ErrorBox> {
+        let mut clicks: Vec<ReferralClick> = vec![];
+        for row in self
+            .w()
+            .await?
+            .query(
+                "SELECT source, clicked FROM referral_clicks WHERE lichessid = $1 ORDER BY clicked",
+                &[&lichess_id],
+            )
+            .await?
+        {
+            clicks.push(ReferralClick {
+                source: row.get(0),
+                clicked: row.get(1),
+            });
+        }
+        Ok(clicks)
+    }
+
+    // `group` is a trusted SQL expression over referral_clicks c.
+    async fn referral_stats(
+        &self,
+        group: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Vec<ReferralStat>, ErrorBox> {
+        let rows = self
+            .w()
+            .await?
+            .query(
+                &format!(
+                    "SELECT {0} AS label, count(*), count(DISTINCT coalesce(c.lichessid, c.visitor)), \
+                    count(DISTINCT c.lichessid) FILTER (WHERE r.converted IS NOT NULL) \
+                    FROM referral_clicks c LEFT JOIN ref r ON r.lichessid = c.lichessid \
+                    GROUP BY label ORDER BY label",
+                    group
+                ),
+                params,
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| ReferralStat {
+                label: row.get(0),
+                clicks: row.get(1),
+                users: row.get(2),
+                conversions: row.get(3),
+            })
+            .collect())
+    }
+
+    pub async fn referral_stats_by_month(
+        &self,
+        timezone: &str,
+    ) -> Result<Vec<ReferralStat>, ErrorBox> {
+        self.referral_stats(
+            "to_char(c.clicked AT TIME ZONE $1, 'YYYY-MM')",
+            &[&timezone],
+        )
+        .await
+    }
+
+    pub async fn referral_stats_by_source(&self) -> Result<Vec<ReferralStat>, ErrorBox> {
+        self.referral_stats("c.source", &[]).await
+    }
+
+    pub async fn has_referral_click(&self, lichess_id: &str) -> Result<bool, ErrorBox> {
+        let rows = self
+            .w()
+            .await?
+            .query("SELECT 1 FROM ref WHERE lichessid = $1", &[&lichess_id])
+            .await?;
+        Ok(!rows.is_empty())
+    }
+
+    pub async fn referral_count(&self) -> Result<i64, ErrorBox> {
+        let rows = self
+            .w()
+            .await?
+            .query("SELECT COUNT(*) FROM ref", &[])
+            .await?;
+        Ok(rows.first().ok_or("no row returned")?.get(0))
+    }
+
+    pub async fn get_referrals(&self) -> Result<Vec<String>, ErrorBox> {
+        let rows = self
+            .w()
+            .await?
+            .query("SELECT lichessid FROM ref", &[])
+            .await?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    pub async fn audit(
+        &self,
+        event: &str,
+        lichess_id: Option<&str>,
+        org_id: Option<&str>,
+        details: &str,
+    ) -> Result<u64, ErrorBox> {
+        let result = self
+            .w()
+            .await?
+            .execute(
+                "INSERT INTO audit (event, lichessid, orgid, details) VALUES ($1, $2, $3, $4)",
+                &[&event, &lichess_id, &org_id, &details],
+            )
+            .await?;
+        Ok(result)
+    }
+
+    pub async fn get_audit_events_for_lichess_id(
+        &self,
+        lichess_id: &str,
+    ) -> Result<Vec<AuditEvent>, ErrorBox> {
+        let mut events: Vec<AuditEvent> = vec![];
+        for row in self
+            .w()
+            .await?
+            .query(
+                "SELECT at, event, orgid, details FROM audit WHERE lichessid = $1 ORDER BY at",
+                &[&lichess_id],
+            )
+            .await?
+        {
+            events.push(AuditEvent {
+                at: row.get(0),
+                event: row.get(1),
+                org_id: row.get(2),
+                details: row.get(3),
+            });
+        }
+        Ok(events)
+    }
+
+    pub async fn get_link_attempts(
+        &self,
+        kind: &str,
+        subject: &str,
+    ) -> Result<Option<LinkLockout>, ErrorBox> {
+        let rows = self
+            .w()
+            .await?
+            .query(
+                "SELECT kind, subject, failures, locked_until FROM link_attempts \
+                WHERE kind = $1 AND subject = $2",
+                &[&kind, &subject],
+            )
+            .await?;
+        Ok(rows.first().map(|row| LinkLockout {
+            kind: row.get(0),
+            subject: row.get(1),
+            failures: row.get(2),
+            locked_until: row.get(3),
+        }))
+    }
+
+    pub async fn get_link_lockout(
+        &self,
+        kind: &str,
+        subject: &str,
+    ) -> Result<Option<DateTime<Utc>>, ErrorBox> {
+        let rows = self
+            .w()
+            .await?
+            .query(
+                "SELECT locked_until FROM link_attempts WHERE kind = $1 AND subject = $2 AND locked_until > now()",
+                &[&kind, &subject],
+            )
+            .await?;
+        Ok(rows.first().and_then(|row| row.get(0)))
+    }
+
+    // Counts an attempt against `subject` before it is verified, unless it is locked out, and locks
+    // it out for `lockouts[i]` seconds when this is failure `free_attempts + i`, or for the last
+    // entry beyond that. Returns the failure count, or None when the subject was already locked out.
+    // Concurrent attempts wait on the row, so each one sees the count and lockout left by the one
+    // before.
+    pub async fn reserve_link_attempt(
+        &self,
+        kind: &str,
+        subject: &str,
+        free_attempts: i32,
+        lockouts: &[i64],
+        forget_after_seconds: i64,
+    ) -> Result<Option<i32>, ErrorBox> {
+        let forget_after = forget_after_seconds as f64;
+        let lockouts: Vec<f64> = lockouts.iter().map(|&seconds| seconds as f64).collect();
+        let failures = "CASE WHEN link_attempts.last_failure < now() - make_interval(secs => $5) \
+            THEN 1 ELSE link_attempts.failures + 1 END";
+        let rows = self
+            .w()
+            .await?
+            .query(
+                &format!(
+                    "INSERT INTO link_attempts (kind, subject, failures, last_failure, locked_until) \
+                    VALUES ($1, $2, 1, now(), CASE WHEN 1 >= $3::integer THEN now() + \
+                    make_interval(secs => ($4::float8[])[LEAST(2 - $3, cardinality($4))]) END) \
+                    ON CONFLICT (kind, subject) DO UPDATE SET failures = {failures}, last_failure = now(), \
+                    locked_until = CASE WHEN {failures} >= $3 THEN now() + \
+                    make_interval(secs => $4[LEAST({failures} - $3 + 1, cardinality($4))]) END \
+                    WHERE link_attempts.locked_until IS NULL OR link_attempts.locked_until <= now() \
+                    RETURNING failures"
+                ),
+                &[&kind, &subject, &free_attempts, &lockouts, &forget_after],
+            )
+            .await?;
+        Ok(rows.first().map(|row| row.get(0)))
+    }
+
+    pub async fn clear_link_attempts(&self, kind: &str, subject: &str) -> Result<u64, ErrorBox> {
+        let result = self
+            .w()
+            .await?
+            .execute(
+                "DELETE FROM link_attempts WHERE kind = $1 AND subject = $2",
+                &[&kind, &subject],
+            )
+            .await?;
+        Ok(result)
+    }
+
+    pub async fn get_link_lockouts(&self) -> Result<Vec<LinkLockout>, ErrorBox> {
+        let mut lockouts: Vec<LinkLockout> = vec![];
+        for row in self
+            .w()
+            .await?
+            .query(
+                "SELECT kind, subject, failures, locked_until FROM link_attempts \
+                WHERE locked_until > now() ORDER BY locked_until DESC",
+                &[],
+            )
+            .await?
+        {
+            lockouts.push(LinkLockout {
+                kind: row.get(0),
+                subject: row.get(1),
+                failures: row.get(2),
+                locked_until: row.get(3),
+            });
+        }
+        Ok(lockouts)
+    }
+
+    pub async fn enqueue_kick(
+        &self,
+        lichess_id: &str,
+        org_id: &str,
+        exp_year: i32,
+    ) -> Result<u64, ErrorBox> {
+        let result = self
+            .w()
+            .await?
+            .execute(
+                "INSERT INTO kick_queue (lichessid, orgid, exp) SELECT $1::varchar, $2::varchar, $3::integer \
+                WHERE NOT EXISTS (SELECT 1 FROM kick_queue WHERE lichessid = $1 AND exp = $3 \
+                AND state IN ('pending', 'failed', 'dismissed')) \
+                ON CONFLICT (lichessid) WHERE state = 'pending' DO NOTHING",
+                &[&lichess_id, &org_id, &exp_year],
+            )
+            .await?;
+        Ok(result)
+    }
+
+    pub async fn get_due_kicks(&self, limit: i64) -> Result<Vec<QueuedKick>, ErrorBox> {
+        let rows = self
+            .w()
+            .await?
+            .query(
+                "SELECT id, lichessid, orgid, exp, attempts, last_error, next_attempt FROM kick_queue \
+                WHERE state = 'pending' AND next_attempt <= now() ORDER BY next_attempt LIMIT $1",
+                &[&limit],
+            )
+            .await?;
+        Ok(extract_queued_kicks(&rows))
+    }
+
+    pub async fn get_kicks_in_state(&self, state: &str) -> Result<Vec<QueuedKick>, ErrorBox> {
+        let rows = self
+            .w()
+            .await?
+            .query(
+                "SELECT id, lichessid, orgid, exp, attempts, last_error, next_attempt FROM kick_queue \
+                WHERE state = $1 ORDER BY next_attempt",
+                &[&state],
+            )
+            .await?;
+        Ok(extract_queued_kicks(&rows))
+    }
+
+    pub async fn get_kicks_for_lichess_id(
+        &self,
+        lichess_id: &str,
+    ) -> Result<Vec<QueuedKick>, ErrorBox> {
+        let rows = self
+            .w()
+            .await?
+            .query(
+                "SELECT id, lichessid, orgid, exp, attempts, last_error, next_attempt FROM kick_queue \
+                WHERE lichessid = $1 ORDER BY created",
+                &[&lichess_id],
+            )
+            .await?;
+        Ok(extract_queued_kicks(&rows))
+    }
+
+    pub async fn get_webhooks_for_lichess_id(
+        &self,
+        lichess_id: &str,
+    ) -> Result<Vec<WebhookDelivery>, ErrorBox> {
+        let rows = self
+            .w()
+            .await?
+            .query(
+                "SELECT url, event, payload, state, created FROM webhook_queue \
+                WHERE lichessid = $1 ORDER BY created",
+                &[&lichess_id],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| WebhookDelivery {
+                url: row.get(0),
+                event: row.get(1),
+                payload: row.get(2),
+                state: row.get(3),
+                created: row.get(4),
+            })
+            .collect())
+    }
+
+    pub async fn get_writebacks_for_lichess_id(
+        &self,
+        lichess_id: &str,
+    ) -> Result<Vec<WritebackRecord>, ErrorBox> {
+        let rows = self
+            .w()
+            .await?
+            .query(
+                "SELECT orgid, lichessid, state, created FROM writeback_queue \
+                WHERE lichessid = $1 ORDER BY created",
+                &[&lichess_id],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| WritebackRecord {
+                org_id: row.get(0),
+                lichess_id: row.get(1),
+                state: row.get(2),
+                created: row.get(3),
+            })
+            .collect())
+    }
+
+    pub async fn get_tournament_results_for_lichess_id(
+        &self,
+        lichess_id: &str,
+    ) -> Result<Vec<TournamentResultRecord>, ErrorBox> {
+        let rows = self
+            .w()
+            .await?
+            .query(
+                "SELECT r.tournamentid, t.name, t.starts, r.rank, r.score FROM tournament_results r \
+                JOIN tournaments t ON t.id = r.tournamentid WHERE r.lichessid = $1 ORDER BY t.starts",
+                &[&lichess_id],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| TournamentResultRecord {
+                tournament_id: row.get(0),
+                name: row.get(1),
+                starts: row.get(2),
+                rank: row.get(3),
+                score: row.get(4),
+            })
+            .collect())
+    }
+
+    pub async fn set_kick_state(
+        &self,
+        id: i32,
+        state: &str,
+        last_error: &str,
+    ) -> Result<u64, ErrorBox> {
+        let result = self
+            .w()
+            .await?
+            .execute(
+                "UPDATE kick_queue SET state = $2, last_error = $3 WHERE id = $1",
+                &[&id, &state, &last_error],
+            )
+            .await?;
+        Ok(result)
+    }
+
+    pub async fn reschedule_kick(
+        &self,
+        id: i32,
+        attempts: i32,
+        next_attempt: DateTime<Utc>,
+        last_error: &str,
+    ) -> Result<u64, ErrorBox> {
+        let result = self
+            .w()
+            .await?
+            .execute(
+                "UPDATE kick_queue SET attempts = $2, next_attempt = $3, last_error = $4 WHERE id = $1",
+                &[&id, &attempts, &next_attempt, &last_error],
+            )
+            .await?;
+        Ok(result)
+    }
+
+    pub async fn enqueue_webhook(
+        &self,
+        url: &str,
+        event: &str,
+        lichess_id: &str,
+        payload: &str,
+    ) -> Result<u64, ErrorBox> {
+        let result = self
+            .w()
+            .await?
+            .execute(
+                "INSERT INTO webhook_queue (url, event, lichessid, payload) VALUES ($1, $2, $3, $4)",
+                &[&url, &event, &lichess_id, &payload],
+            )
+            .await?;
+        Ok(result)
+    }
+
+    pub async fn get_due_webhooks(&self, limit: i64) -> Result<Vec<QueuedWebhook>, ErrorBox> {
+        let rows = self
+            .w()
+            .await?
+            .query(
+                "SELECT id, url, event, payload, attempts FROM webhook_queue \
+                WHERE state = 'pending' AND next_attempt <= now() ORDER BY next_attempt, id LIMIT $1",
+                &[&limit],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| QueuedWebhook {
+                id: row.get(0),
+                url: row.get(1),
+                event: row.get(2),
+                payload: row.get(3),
+                attempts: row.get(4),
+            })
+            .collect())
+    }
+
+    pub async fn set_webhook_state(
+        &self,
+        id: i32,
+        state: &str,
+        last_error: &str,
+    ) -> Result<u64, ErrorBox> {
+        let result = self
+            .w()
+            .await?
+            .execute(
+                "UPDATE webhook_queue SET state = $2, last_error = $3 WHERE id = $1",
+                &[&id, &state, &last_error],
+            )
+            .await?;
+        Ok(result)
+    }
+
+    pub async fn reschedule_webhook(
+        &self,
+        id: i32,
+        attempts: i32,
+        next_attempt: DateTime<Utc>,
+        last_error: &str,
+    ) -> Result<u64, ErrorBox> {
+        let result = self
+            .w()
+            .await?
+            .execute(
+                "UPDATE webhook_queue SET attempts = $2, next_attempt = $3, last_error = $4 WHERE id = $1",
+                &[&id, &attempts, &next_attempt, &last_error],
+            )
+            .await?;
+        Ok(result)
+    }
+
+    // Only the latest value per member matters, so a pending write for the same member is replaced.
+    pub async fn enqueue_writeback(&self, org_id: &str, lichess_id: &str) -> Result<u64, ErrorBox> {
+        let result = self
+            .w()
+            .await?
+            .execute(
+                "INSERT INTO writeback_queue (orgid, lichessid) VALUES ($1, $2) \
+                ON CONFLICT (orgid) WHERE state = 'pending' \
+                DO UPDATE SET lichessid = $2, attempts = 0, next_attempt = now(), last_error = ''",
+                &[&org_id, &lichess_id],
+            )
+            .await?;
+        Ok(result)
+    }
+
+    // Queues the current Lichess ID of every linked member.
+    pub async fn enqueue_all_writebacks(&self) -> Result<u64, ErrorBox> {
+        let result = self
+            .w()
+            .await?
+            .execute(
+                "INSERT INTO writeback_queue (orgid, lichessid) SELECT orgid, lichessid FROM memberships \
+                ON CONFLICT (orgid) WHERE state = 'pending' \
+                DO UPDATE SET lichessid = excluded.lichessid, attempts = 0, next_attempt = now(), last_error = ''",
+                &[],
+            )
+            .await?;
+        Ok(result)
+    }
+
+    pub async fn get_due_writebacks(&self, limit: i64) -> Result<Vec<QueuedWriteback>, ErrorBox> {
+        let rows = self
+            .w()
+            .await?
+            .query(
+                "SELECT id, orgid, lichessid, attempts FROM writeback_queue \
+                WHERE state = 'pending' AND next_attempt <= now() ORDER BY next_attempt, id LIMIT $1",
+                &[&limit],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| QueuedWriteback {
+                id: row.get(0),
+                org_id: row.get(1),
+                lichess_id: row.get(2),
+                attempts: row.get(3),
+            })
+            .collect())
+    }
+
+    // Like reschedule_writeback, this leaves the row alone if it was replaced by a newer Lichess ID
+    // while the old one was being written.
+    pub async fn set_writeback_state(
+        &self,
+        writeback: &QueuedWriteback,
+        state: &str,
+        last_error: &str,
+    ) -> Result<u64, ErrorBox> {
+        let result = self
+            .w()
+            .await?
+            .execute(
+                "UPDATE writeback_queue SET state = $3, last_error = $4 WHERE id = $1 AND lichessid = $2",
+                &[&writeback.id, &writeback.lichess_id, &state, &last_error],
+            )
+            .await?;
+        Ok(result)
+    }
+
+    pub async fn reschedule_writeback(
+        &self,
+        writeback: &QueuedWriteback,
+        attempts: i32,
+        next_attempt: DateTime<Utc>,
+        last_error: &str,
+    ) -> Result<u64, ErrorBox> {
+        let result = self
+            .w()
+            .await?
+            .execute(
+                "UPDATE writeback_queue SET attempts = $3, next_attempt = $4, last_error = $5 \
+                WHERE id = $1 AND lichessid = $2",
+                &[
+                    &writeback.id,
+                    &writeback.lichess_id,
+                    &attempts,
+                    &next_attempt,
+                    &last_error,
+                ],
+            )
+            .await?;
+        Ok(result)
+    }
+
+    pub async fn retry_failed_kick(&self, id: i32) -> Result<u64, ErrorBox> {
+        let result = self
+            .w()
+            .await?
+            .execute(
+                "UPDATE kick_queue SET state = 'pending', attempts = 0, next_attempt = now() \
+                WHERE id = $1 AND state = 'failed' \
+                AND NOT EXISTS (SELECT 1 FROM kick_queue p WHERE p.state = 'pending' AND p.lichessid = kick_queue.lichessid)",
+                &[&id],
+            )
+            .await?;
+        Ok(result)
+    }
+
+    pub async fn dismiss_failed_kick(&self, id: i32) -> Result<u64, ErrorBox> {
+        let result = self
+            .w()
+            .await?
+            .execute(
+                "UPDATE kick_queue SET state = 'dismissed' WHERE id = $1 AND state = 'failed'",
+                &[&id],
+            )
+            .await?;
+        Ok(result)
+    }
+
+    // Only one instance can hold the lock for a given job name at a time.
+    pub async fn try_lock_job(&self, name: &str) -> Result<Option<JobLock>, ErrorBox> {
+        let connection = self.0.get_owned().await?;
+        let row = connection
+            .query_one("SELECT pg_try_advisory_lock(hashtext($1))", &[&name])
+            .await?;
+        Ok(if row.get(0) {
+            Some(JobLock {
+                connection,
+                name: name.to_string(),
+            })
+        } else {
+            None
+        })
+    }
+
+    pub async fn get_job_last_run(&self, name: &str) -> Result<Option<DateTime<Utc>>, ErrorBox> {
+        let rows = self
+            .w()
+            .await?
+            .query("SELECT last_run FROM job_runs WHERE name = $1", &[&name])
+            .await?;
+        Ok(rows.first().map(|row| row.get(0)))
+    }
+
+    pub async fn record_job_run(&self, name: &str, at: DateTime<Utc>) -> Result<(), ErrorBox> {
+        self.w()
+            .await?
+            .execute(
+                "INSERT INTO job_runs (name, last_run) VALUES ($1, $2) \
+                ON CONFLICT (name) DO UPDATE SET last_run = $2",
+                &[&name, &at],
+            )
+            .await?;
+        Ok(())
+    }
+
+    // Takes the lease if it is free or expired, or extends it if `holder` already has it.
+    pub async fn acquire_lease(
+        &self,
+        name: &str,
+        holder: &str,
+        lease_seconds: i64,
+    ) -> Result<bool, ErrorBox> {
+        let result = self
+            .w()
+            .await?
+            .execute(
+                "INSERT INTO leases (name, holder, expires) VALUES ($1, $2, now() + make_interval(secs => $3)) \
+                ON CONFLICT (name) DO UPDATE SET holder = $2, expires = now() + make_interval(secs => $3) \
+                WHERE leases.holder = $2 OR leases.expires < now()",
+                &[&name, &holder, &(lease_seconds as f64)],
+            )
+            .await?;
+        Ok(result == 1)
+    }
+
+    pub async fn delete_older_than(&self, target: &Target, days: i64) -> Result<u64, ErrorBox> {
+        let result = self
+            .w()
+            .await?
+            .execute(
+                &format!(
+                    "DELETE FROM {} WHERE {} < now() - make_interval(days => $1) AND NOT ({})",
+                    target.table, target.time_column, target.keep
+                ),
+                &[&(days as i32)],
+            )
+            .await?;
+        Ok(result)
+    }
+
+    // Replaces the identifying columns with a salted hash, so rows can still be counted and
+    // correlated with each other but no longer point to a person.
+    pub async fn anonymise_older_than(
+        &self,
+        target: &Target,
+        days: i64,
+        salt: &str,
+    ) -> Result<u64, ErrorBox> {
+        let assignments: Vec<String> = target
+            .id_columns
+            .iter()
+            .map(|column| {
+                format!(
+                    "{0} = CASE WHEN {0} LIKE 'anon:%' THEN {0} \
+                    ELSE 'anon:' || encode(sha256(convert_to($2 || {0}, 'UTF8')), 'hex') END",
+                    column
+                )
+            })
+            .collect();
+        let not_anonymised: Vec<String> = target
+            .id_columns
+            .iter()
+            .map(|column| format!("{} NOT LIKE 'anon:%'", column))
+            .collect();
+        let result = self
+            .w()
+            .await?
+            .execute(
+                &format!(
+                    "UPDATE {} SET {} WHERE {} < now() - make_interval(days => $1) AND NOT ({}) AND ({})",
+                    target.table,
+                    assignments.join(", "),
+                    target.time_column,
+                    target.keep,
+                    not_anonymised.join(" OR ")
+                ),
+                &[&(days as i32), &salt],
+            )
+            .await?;
+        Ok(result)
+    }
+
+    pub async fn create_api_key(
+        &self,
+        name: &str,
+        prefix: &str,
+        hash: &str,
+        scopes: &[String],
+        created_by: &str,
+    ) -> Result<u64, ErrorBox> {
+        let result = self
+            .w()
+            .await?
+            .execute(
+                "INSERT INTO api_keys (name, prefix, hash, scopes, created_by) VALUES ($1, $2, $3, $4, $5)",
+                &[&name, &prefix, &hash, &scopes.join(" "), &created_by],
+            )
+            .await?;
+        Ok(result)
+    }
+
+    pub async fn get_api_keys(&self) -> Result<Vec<ApiKeyRecord>, ErrorBox> {
+        let rows = self
+            .w()
+            .await?
+            .query(
+                "SELECT id, name, prefix, scopes, created, created_by, last_used, revoked FROM api_keys \
+                ORDER BY revoked IS NOT NULL, created DESC",
+                &[],
+            )
+            .await?;
+        Ok(extract_api_keys(&rows))
+    }
+
+    // Finds the key with this hash unless it was revoked, and records that it was used.
+    pub async fn use_api_key(&self, hash: &str) -> Result<Option<ApiKeyRecord>, ErrorBox> {
+        let rows = self
+            .w()
+            .await?
+            .query(
+                "UPDATE api_keys SET last_used = now() WHERE hash = $1 AND revoked IS NULL \
+                RETURNING id, name, prefix, scopes, created, created_by, last_used, revoked",
+                &[&hash],
+            )
+            .await?;
+        Ok(extract_api_keys(&rows).into_iter().next())
+    }
+
+    pub async fn revoke_api_key(&self, id: i32) -> Result<u64, ErrorBox> {
+        let result = self
+            .w()
+            .await?
+            .execute(
+                "UPDATE api_keys SET revoked = now() WHERE id = $1 AND revoked IS NULL",
+                &[&id],
+            )
+            .await?;
+        Ok(result)
+    }
+
+    pub async fn get_cached_tournament_ids(
+        &self,
+        tournament_ids: &[String],
+    ) -> Result<Vec<String>, ErrorBox> {
+        let rows = self
+            .w()
+            .await?
+            .query(
+                "SELECT id FROM tournaments WHERE id = ANY($1)",
+                &[&tournament_ids],
+            )
+            .await?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    // Stores a finished tournament with all its results. `team_id` is set for tournaments that
+    // were found through the team's tournament list.
+    pub async fn cache_tournament(
+        &self,
+        tournament: &CachedTournament,
+        team_id: &str,
+        results: &[TournamentResultRow],
+    ) -> Result<(), ErrorBox> {
+        let lichess_ids: Vec<&str> = results.iter().map(|r| r.lichess_id.as_str()).collect();
+        let ranks: Vec<i32> = results.iter().map(|r| r.rank).collect();
+        let scores: Vec<f64> = results.iter().map(|r| r.score).collect();
+
+        let mut client = self.w().await?;
+        let transaction = client.transaction().await?;
+        transaction
+            .execute(
+                "INSERT INTO tournaments (id, name, starts, teamid) VALUES ($1, $2, $3, $4) \
+                ON CONFLICT (id) DO NOTHING",
+                &[
+                    &tournament.id,
+                    &tournament.name,
+                    &tournament.starts,
+                    &team_id,
+                ],
+            )
+            .await?;
+        transaction
+            .execute(
+                "INSERT INTO tournament_results (tournamentid, lichessid, rank, score) \
+                SELECT $1, * FROM UNNEST($2::varchar[], $3::integer[], $4::double precision[]) \
+                ON CONFLICT DO NOTHING",
+                &[&tournament.id, &lichess_ids, &ranks, &scores],
+            )
+            .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    // The cached tournaments of a series, in the order they were played: those listed by ID, and
+    // with a non-empty `team_id` the team's tournaments that started between `since` and `until`.
+    pub async fn get_series_tournaments(
+        &self,
+        tournament_ids: &[String],
+        team_id: &str,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<Vec<CachedTournament>, ErrorBox> {
+        let rows = self
+            .w()
+            .await?
+            .query(
+                "SELECT id, name, starts FROM tournaments WHERE id = ANY($1) \
+                OR ($2 <> '' AND teamid = $2 \
+                AND ($3::timestamptz IS NULL OR starts >= $3) \
+                AND ($4::timestamptz IS NULL OR starts < $4)) \
+                ORDER BY starts, id",
+                &[&tournament_ids, &team_id, &since, &until],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| CachedTournament {
+                id: row.get(0),
+                name: row.get(1),
+                starts: row.get(2),
+            })
+            .collect())
+    }
+
+    // Results of currently linked members only, best rank first within each tournament.
+    pub async fn get_linked_results(
+        &self,
+        tournament_ids: &[String],
+    ) -> Result<Vec<LinkedResult>, ErrorBox> {
+        let rows = self
+            .w()
+            .await?
+            .query(
+                "SELECT r.tournamentid, r.lichessid, r.rank FROM tournament_results r \
+                JOIN memberships m ON m.lichessid = r.lichessid \
+                WHERE r.tournamentid = ANY($1) ORDER BY r.tournamentid, r.rank",
+                &[&tournament_ids],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| LinkedResult {
+                tournament_id: row.get(0),
+                lichess_id: row.get(1),
+                rank: row.get(2),
+            })
+            .collect())
     }
 }
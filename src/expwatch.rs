@@ -1,12 +1,49 @@
-use crate::db::{Membership, OrgDbClient};
-use crate::lichess;
+use crate::announce;
+use crate::config::{AzolveConfig, Config, KickQueueConfig, WebhookConfig};
+use crate::db::{self, Membership, OrgDbClient, QueuedKick};
+use crate::leader::Leadership;
+use crate::lichess::{self, KickOutcome};
 use crate::org;
+use crate::retry;
+use crate::schedule;
 use crate::textlog;
 use crate::types::*;
+use crate::webhooks;
+use chrono::{Duration, Utc};
 use chrono_tz::Tz;
-use std::thread;
+use cron::Schedule;
+use rocket::tokio::time::sleep;
 
-async fn find_expired_members(
+const JOB_NAME: &str = "expiry";
+
+// How long to wait after a 429 from Lichess without a usable Retry-After header.
+const DEFAULT_RATE_LIMIT_SECONDS: u64 = 60;
+const QUEUE_BATCH_SIZE: i64 = 50;
+
+#[derive(Clone)]
+pub struct KickSettings {
+    pub lichess_domain: String,
+    pub team_id: String,
+    pub api_token: String,
+    pub queue: KickQueueConfig,
+    pub webhooks: WebhookConfig,
+    pub azolve: AzolveConfig,
+}
+
+impl KickSettings {
+    pub fn from_config(config: &Config) -> KickSettings {
+        KickSettings {
+            lichess_domain: config.lichess.domain.clone(),
+            team_id: config.org.team_id.clone(),
+            api_token: config.lichess.personal_api_token.clone(),
+            queue: config.kick_queue.clone(),
+            webhooks: config.webhooks.clone(),
+            azolve: config.azolve.clone(),
+        }
+    }
+}
+
+pub async fn find_expired_members(
     db: &OrgDbClient,
     timezone: Tz,
     month: u32,
@@ -14,7 +51,9 @@ async fn find_expired_members(
 ) -> Result<Vec<Membership>, ErrorBox> {
     let current_year = org::current_year(timezone);
 
-    let year = if org::is_past_renewal(current_year, timezone, month, day) {
+    let year = if org::is_past_renewal(current_year, timezone, month, day)
+        .ok_or("the renewal date does not exist this year")?
+    {
         current_year
     } else {
         current_year - 1
@@ -23,120 +62,217 @@ async fn find_expired_members(
     db.get_members_with_at_most_expiry_year(year).await
 }
 
-async fn clean_expired_members(
-    expired_members: Vec<Membership>,
-    delay_ms: u64,
+pub async fn enqueue_expired_members(
     db: &OrgDbClient,
-    http_client: &reqwest::Client,
-    lichess_domain: &str,
-    team_id: &str,
-    api_token: &str,
-) {
-    for member in expired_members {
-        if lichess::kick_from_team(
-            &http_client,
-            &api_token,
-            &lichess_domain,
-            &team_id,
-            &member.lichess_id,
+    timezone: Tz,
+    renewal_month: u32,
+    renewal_day: u32,
+) -> Result<u64, ErrorBox> {
+    let mut queued = 0;
+    for member in find_expired_members(db, timezone, renewal_month, renewal_day).await? {
+        queued += db
+            .enqueue_kick(&member.lichess_id, &member.org_id, member.exp_year)
+            .await?;
+    }
+    Ok(queued)
+}
+
+// The membership may have been renewed, relinked or removed since the kick was queued.
+async fn is_still_expired(db: &OrgDbClient, kick: &QueuedKick) -> Result<bool, ErrorBox> {
+    Ok(match db.get_member_for_org_id(&kick.org_id).await? {
+        Some(member) => member.lichess_id == kick.lichess_id && member.exp_year <= kick.exp_year,
+        None => false,
+    })
+}
+
+async fn fail_kick(
+    db: &OrgDbClient,
+    settings: &KickSettings,
+    kick: &QueuedKick,
+    error: &str,
+) -> Result<(), ErrorBox> {
+    let attempts = kick.attempts + 1;
+    textlog::append_line_to(
+        "kick.error.log",
+        &format!(
+            "Could not kick {} (attempt {}): {}",
+            &kick.lichess_id, attempts, error
+        ),
+    )
+    .unwrap_or(());
+    println!("Could not kick {}: {}", &kick.lichess_id, error);
+
+    if attempts >= settings.queue.max_attempts {
+        db.reschedule_kick(kick.id, attempts, Utc::now(), error)
+            .await?;
+        db.set_kick_state(kick.id, db::KICK_FAILED, error).await?;
+    } else {
+        let backoff = retry::backoff_delay(
+            attempts,
+            settings.queue.base_retry_seconds,
+            retry::MAX_RETRY_SECONDS,
+        );
+        db.reschedule_kick(
+            kick.id,
+            attempts,
+            Utc::now() + Duration::seconds(backoff),
+            error,
         )
-        .await
-        {
-            match db.remove_membership(&member.org_id).await {
+        .await?;
+    }
+    Ok(())
+}
+
+// Returns how long to pause the whole queue when Lichess rate limits us.
+async fn process_kick(
+    db: &OrgDbClient,
+    http_client: &reqwest::Client,
+    settings: &KickSettings,
+    kick: &QueuedKick,
+) -> Result<Option<u64>, ErrorBox> {
+    if !is_still_expired(db, kick).await? {
+        db.set_kick_state(kick.id, db::KICK_CANCELLED, "").await?;
+        return Ok(None);
+    }
+
+    match lichess::kick_from_team_outcome(
+        http_client,
+        &settings.api_token,
+        &settings.lichess_domain,
+        &settings.team_id,
+        &kick.lichess_id,
+    )
+    .await
+    {
+        KickOutcome::Kicked => {
+            match db.end_membership(&kick.org_id, db::ENDED_KICKED).await {
                 Ok(_) => {
                     textlog::append_line_to(
                         "kick.log",
-                        &format!("Successfully kicked {}", &member.lichess_id),
+                        &format!("Successfully kicked {}", &kick.lichess_id),
                     )
                     .unwrap_or(());
-                    println!("Successfully kicked {}", &member.lichess_id);
+                    println!("Successfully kicked {}", &kick.lichess_id);
                 }
                 _ => {
                     textlog::append_line_to(
                         "kick.error.log",
-                        &format!("Could not Remove {} from db", &member.lichess_id),
+                        &format!("Could not Remove {} from db", &kick.lichess_id),
                     )
                     .unwrap_or(());
-                    println!("Could not remove {} from db", &member.lichess_id);
+                    println!("Could not remove {} from db", &kick.lichess_id);
                 }
             }
-        } else {
-            textlog::append_line_to(
-                "kick.error.log",
-                &format!("Could not kick {}", &member.lichess_id),
+            db.set_kick_state(kick.id, db::KICK_DONE, "").await?;
+            announce::ended(
+                db,
+                &settings.webhooks,
+                &settings.azolve,
+                webhooks::KICKED,
+                &Membership {
+                    org_id: kick.org_id.clone(),
+                    lichess_id: kick.lichess_id.clone(),
+                    exp_year: kick.exp_year,
+                },
             )
-            .unwrap_or(());
-            println!("Could not kick {}", &member.lichess_id);
+            .await?;
+            Ok(None)
+        }
+        KickOutcome::RateLimited(retry_after) => {
+            let seconds = retry_after.unwrap_or(DEFAULT_RATE_LIMIT_SECONDS);
+            db.reschedule_kick(
+                kick.id,
+                kick.attempts,
+                Utc::now() + Duration::seconds(seconds as i64),
+                "Rate limited by Lichess",
+            )
+            .await?;
+            println!("Rate limited by Lichess, pausing kicks for {}s", seconds);
+            Ok(Some(seconds))
+        }
+        KickOutcome::Failed(error) => {
+            fail_kick(db, settings, kick, &error).await?;
+            Ok(None)
         }
-
-        thread::sleep(std::time::Duration::from_millis(delay_ms));
     }
 }
 
-#[allow(clippy::too_many_arguments)]
-async fn find_and_clean_expired(
-    delay_ms: u64,
+pub async fn process_kick_queue(
     db: &OrgDbClient,
     http_client: &reqwest::Client,
-    lichess_domain: &str,
-    team_id: &str,
-    api_token: &str,
-    timezone: Tz,
-    renewal_month: u32,
-    renewal_day: u32,
-) {
-    match find_expired_members(&db, timezone, renewal_month, renewal_day).await {
-        Ok(expired) => {
-            clean_expired_members(
-                expired,
-                delay_ms,
-                &db,
-                &http_client,
-                &lichess_domain,
-                &team_id,
-                &api_token,
-            )
-            .await
+    settings: &KickSettings,
+    leadership: &Leadership,
+) -> Result<(), ErrorBox> {
+    loop {
+        let due = db.get_due_kicks(QUEUE_BATCH_SIZE).await?;
+        if due.is_empty() {
+            return Ok(());
         }
-        _ => {
-            textlog::append_line_to("expiry.error.log", "Could not fetch expired users from db")
-                .unwrap_or(());
-            println!("Could not fetch expired users from db");
+        for kick in &due {
+            if !leadership.is_leader() {
+                return Ok(());
+            }
+            if let Some(pause) = process_kick(db, http_client, settings, kick).await? {
+                sleep(std::time::Duration::from_secs(pause)).await;
+                break;
+            }
+            sleep(std::time::Duration::from_millis(settings.queue.delay_ms)).await;
         }
     }
 }
 
-#[allow(clippy::too_many_arguments)]
 pub fn launch(
     db_client: OrgDbClient,
-    lichess_domain: String,
-    team_id: String,
-    api_token: String,
-    interval_seconds: u64,
+    settings: KickSettings,
+    leadership: Leadership,
+    expiry_schedule: Schedule,
     timezone: Tz,
     renewal_month: u32,
     renewal_day: u32,
 ) {
+    let queue_db_client = db_client.clone();
+    let poll_seconds = settings.queue.poll_seconds;
+
+    let scan_db_client = db_client.clone();
+    rocket::tokio::task::spawn(schedule::run_scheduled(
+        db_client,
+        JOB_NAME,
+        expiry_schedule,
+        timezone,
+        leadership.clone(),
+        move || {
+            let db_client = scan_db_client.clone();
+            async move {
+                println!("Finding expired members...");
+                let queued =
+                    enqueue_expired_members(&db_client, timezone, renewal_month, renewal_day)
+                        .await?;
+                println!("Queued {} expired members to be kicked", queued);
+                Ok(())
+            }
+        },
+    ));
+
     rocket::tokio::task::spawn(async move {
         let http_client = reqwest::Client::new();
 
         loop {
-            println!("Finding and cleaning expired members...");
-
-            find_and_clean_expired(
-                1000,
-                &db_client,
-                &http_client,
-                &lichess_domain,
-                &team_id,
-                &api_token,
-                timezone,
-                renewal_month,
-                renewal_day,
-            )
-            .await;
+            if !leadership.is_leader() {
+                sleep(std::time::Duration::from_secs(poll_seconds)).await;
+                continue;
+            }
+            if let Err(e) =
+                process_kick_queue(&queue_db_client, &http_client, &settings, &leadership).await
+            {
+                textlog::append_line_to(
+                    "expiry.error.log",
+                    &format!("Could not process kick queue: {}", e),
+                )
+                .unwrap_or(());
+                println!("Could not process kick queue: {}", e);
+            }
 
-            rocket::tokio::time::sleep(std::time::Duration::from_secs(interval_seconds)).await;
+            sleep(std::time::Duration::from_secs(poll_seconds)).await;
         }
     });
 }
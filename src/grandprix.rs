@@ -0,0 +1,463 @@
+use crate::config::{Config, GrandPrixConfig, GrandPrixSeries, LichessConfig};
+use crate::db::{CachedTournament, LinkedResult, OrgDbClient, TournamentResultRow};
+use crate::leader::Leadership;
+use crate::lichess::{self, TournamentKind};
+use crate::org;
+use crate::schedule;
+use crate::textlog;
+use crate::types::*;
+use chrono::{DateTime, Days, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+use cron::Schedule;
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+
+const JOB_NAME: &str = "grand_prix";
+const LOG_FILE: &str = "grandprix.log";
+
+// How far back the team's tournament list is read when discovering tournaments.
+const TEAM_ARENA_LIMIT: u32 = 200;
+
+#[derive(Clone)]
+pub struct GrandPrixSettings {
+    pub grand_prix: GrandPrixConfig,
+    pub lichess: LichessConfig,
+    pub team_id: String,
+    pub timezone: Tz,
+}
+
+impl GrandPrixSettings {
+    pub fn from_config(config: &Config) -> Result<GrandPrixSettings, ErrorBox> {
+        Ok(GrandPrixSettings {
+            grand_prix: config.grand_prix.clone(),
+            lichess: config.lichess.clone(),
+            team_id: config.org.team_id.clone(),
+            timezone: org::timezone_from_string(&config.org.timezone)?,
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct Standing {
+    pub rank: usize,
+    pub lichess_id: String,
+    pub total: i32,
+    pub events: usize,
+    // Per tournament of the series, in order; None where the member did not take part.
+    pub points: Vec<Option<i32>>,
+}
+
+#[derive(Serialize)]
+pub struct SeriesStandings {
+    pub name: String,
+    pub slug: String,
+    pub tournaments: Vec<CachedTournament>,
+    pub standings: Vec<Standing>,
+}
+
+fn start_of_day(date: NaiveDate, timezone: Tz) -> Option<DateTime<Utc>> {
+    timezone
+        .from_local_datetime(&date.and_hms_opt(0, 0, 0)?)
+        .earliest()
+        .map(|time| time.with_timezone(&Utc))
+}
+
+// The start of `since` and the end of `until`, in the organisation's timezone.
+fn window(
+    series: &GrandPrixSeries,
+    timezone: Tz,
+) -> (Option<DateTime<Utc>>, Option<DateTime<Utc>>) {
+    let since = NaiveDate::parse_from_str(&series.since, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| start_of_day(date, timezone));
+    let until = NaiveDate::parse_from_str(&series.until, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.checked_add_days(Days::new(1)))
+        .and_then(|date| start_of_day(date, timezone));
+    (since, until)
+}
+
+async fn cache_results(
+    db: &OrgDbClient,
+    http_client: &reqwest::Client,
+    settings: &GrandPrixSettings,
+    tournament: CachedTournament,
+    team_id: &str,
+) -> Result<(), ErrorBox> {
+    let results = lichess::get_tournament_results(
+        http_client,
+        &settings.lichess.personal_api_token,
+        &settings.lichess.domain,
+        TournamentKind::Arena,
+        &tournament.id,
+    )
+    .await?
+    .ok_or("Lichess has no results for this tournament")?;
+    let rows: Vec<TournamentResultRow> = results
+        .into_iter()
+        .map(|result| TournamentResultRow {
+            lichess_id: result.username.to_lowercase(),
+            rank: result.rank as i32,
+            score: result.score.or(result.points).unwrap_or(0.0),
+        })
+        .collect();
+    db.cache_tournament(&tournament, team_id, &rows).await?;
+    textlog::log_to(
+        LOG_FILE,
+        &format!(
+            "Cached {} result(s) of {} ({})",
+            rows.len(),
+            &tournament.id,
+            &tournament.name
+        ),
+    );
+    Ok(())
+}
+
+// Fetches the results of every finished tournament of the configured series that is not cached
+// yet. Results never change once a tournament has finished, so each one is fetched only once.
+pub async fn refresh(
+    db: &OrgDbClient,
+    http_client: &reqwest::Client,
+    settings: &GrandPrixSettings,
+) -> Result<(), ErrorBox> {
+    let token = &settings.lichess.personal_api_token;
+    let domain = &settings.lichess.domain;
+
+    let listed: Vec<String> = settings
+        .grand_prix
+        .series
+        .iter()
+        .flat_map(|series| series.tournaments.iter().cloned())
+        .collect::<BTreeSet<String>>()
+        .into_iter()
+        .collect();
+    let cached = db.get_cached_tournament_ids(&listed).await?;
+    for tournament_id in listed.iter().filter(|id| !cached.contains(id)) {
+        let arena = match lichess::get_arena(http_client, token, domain, tournament_id).await {
+            Ok(Some(arena)) => arena,
+            Ok(None) => {
+                textlog::log_to(
+                    LOG_FILE,
+                    &format!("Lichess does not know tournament {}", tournament_id),
+                );
+                continue;
+            }
+            Err(e) => {
+                textlog::log_to(
+                    LOG_FILE,
+                    &format!("Could not fetch tournament {}: {}", tournament_id, e),
+                );
+                continue;
+            }
+        };
+        if !arena.is_finished {
+            continue;
+        }
+        let Ok(starts) = DateTime::parse_from_rfc3339(&arena.starts_at) else {
+            textlog::log_to(
+                LOG_FILE,
+                &format!(
+                    "Tournament {} has an invalid start time {}",
+                    tournament_id, &arena.starts_at
+                ),
+            );
+            continue;
+        };
+        let tournament = CachedTournament {
+            id: arena.id,
+            name: arena.full_name,
+            starts: starts.with_timezone(&Utc),
+        };
+        if let Err(e) = cache_results(db, http_client, settings, tournament, "").await {
+            textlog::log_to(
+                LOG_FILE,
+                &format!("Could not cache tournament {}: {}", tournament_id, e),
+            );
+        }
+    }
+
+    let windows: Vec<_> = settings
+        .grand_prix
+        .series
+        .iter()
+        .filter(|series| series.discover)
+        .map(|series| window(series, settings.timezone))
+        .collect();
+    if windows.is_empty() {
+        return Ok(());
+    }
+    let now = Utc::now();
+    let arenas = lichess::get_team_arenas(
+        http_client,
+        token,
+        domain,
+        &settings.team_id,
+        TEAM_ARENA_LIMIT,
+    )
+    .await?;
+    let ids: Vec<String> = arenas.iter().map(|arena| arena.id.clone()).collect();
+    let cached = db.get_cached_tournament_ids(&ids).await?;
+    for arena in arenas {
+        let (Some(starts), Some(finishes)) = (
+            DateTime::from_timestamp_millis(arena.starts_at),
+            DateTime::from_timestamp_millis(arena.finishes_at),
+        ) else {
+            continue;
+        };
+        let in_series = windows.iter().any(|(since, until)| {
+            since.is_none_or(|since| starts >= since) && until.is_none_or(|until| starts < until)
+        });
+        if finishes > now || !in_series || cached.contains(&arena.id) {
+            continue;
+        }
+        let tournament_id = arena.id.clone();
+        let tournament = CachedTournament {
+            id: arena.id,
+            name: arena.full_name,
+            starts,
+        };
+        if let Err(e) =
+            cache_results(db, http_client, settings, tournament, &settings.team_id).await
+        {
+            textlog::log_to(
+                LOG_FILE,
+                &format!("Could not cache tournament {}: {}", tournament_id, e),
+            );
+        }
+    }
+    Ok(())
+}
+
+// Scores each tournament by the members' places in it, and ranks members by their total over
+// their `best_of` best results.
+fn score(
+    config: &GrandPrixConfig,
+    tournaments: &[CachedTournament],
+    results: Vec<LinkedResult>,
+) -> Vec<Standing> {
+    let columns: HashMap<&str, usize> = tournaments
+        .iter()
+        .enumerate()
+        .map(|(i, tournament)| (tournament.id.as_str(), i))
+        .collect();
+    let mut points_by_member: HashMap<String, Vec<Option<i32>>> = HashMap::new();
+    let mut place = 0;
+    let mut previous_tournament = String::new();
+    for result in results {
+        let Some(&column) = columns.get(result.tournament_id.as_str()) else {
+            continue;
+        };
+        if result.tournament_id != previous_tournament {
+            place = 0;
+            previous_tournament = result.tournament_id.clone();
+        }
+        place += 1;
+        let position = if config.rank_among_members {
+            place
+        } else {
+            result.rank as usize
+        };
+        let points = config
+            .points
+            .get(position.saturating_sub(1))
+            .copied()
+            .unwrap_or(config.participation_points);
+        points_by_member
+            .entry(result.lichess_id)
+            .or_insert_with(|| vec![None; tournaments.len()])[column] = Some(points);
+    }
+
+    let mut standings: Vec<Standing> = points_by_member
+        .into_iter()
+        .map(|(lichess_id, points)| {
+            let mut counted: Vec<i32> = points.iter().flatten().copied().collect();
+            counted.sort_unstable_by(|a, b| b.cmp(a));
+            if config.best_of > 0 {
+                counted.truncate(config.best_of);
+            }
+            Standing {
+                rank: 0,
+                lichess_id,
+                total: counted.iter().sum(),
+                events: points.iter().flatten().count(),
+                points,
+            }
+        })
+        .collect();
+    standings.sort_by(|a, b| {
+        b.total
+            .cmp(&a.total)
+            .then_with(|| a.lichess_id.cmp(&b.lichess_id))
+    });
+    for i in 0..standings.len() {
+        standings[i].rank = if i > 0 && standings[i].total == standings[i - 1].total {
+            standings[i - 1].rank
+        } else {
+            i + 1
+        };
+    }
+    standings
+}
+
+// The standings of a series from the cached results, listing currently linked members only.
+pub async fn standings(
+    db: &OrgDbClient,
+    config: &GrandPrixConfig,
+    team_id: &str,
+    timezone: Tz,
+    series: &GrandPrixSeries,
+) -> Result<SeriesStandings, ErrorBox> {
+    let (since, until) = window(series, timezone);
+    let tournaments = db
+        .get_series_tournaments(
+            &series.tournaments,
+            if series.discover { team_id } else { "" },
+            since,
+            until,
+        )
+        .await?;
+    let ids: Vec<String> = tournaments
+        .iter()
+        .map(|tournament| tournament.id.clone())
+        .collect();
+    let results = db.get_linked_results(&ids).await?;
+    Ok(SeriesStandings {
+        name: series.name.clone(),
+        slug: series.slug.clone(),
+        standings: score(config, &tournaments, results),
+        tournaments,
+    })
+}
+
+pub fn launch(
+    db_client: OrgDbClient,
+    settings: GrandPrixSettings,
+    leadership: Leadership,
+    refresh_schedule: Schedule,
+) {
+    let refresh_db_client = db_client.clone();
+    let timezone = settings.timezone;
+    rocket::tokio::task::spawn(schedule::run_scheduled(
+        db_client,
+        JOB_NAME,
+        refresh_schedule,
+        timezone,
+        leadership,
+        move || {
+            let db_client = refresh_db_client.clone();
+            let settings = settings.clone();
+            async move { refresh(&db_client, &reqwest::Client::new(), &settings).await }
+        },
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(rank_among_members: bool, best_of: usize) -> GrandPrixConfig {
+        GrandPrixConfig {
+            points: vec![10, 6, 4],
+            participation_points: 1,
+            rank_among_members,
+            best_of,
+            ..Default::default()
+        }
+    }
+
+    fn tournaments(ids: &[&str]) -> Vec<CachedTournament> {
+        ids.iter()
+            .map(|id| CachedTournament {
+                id: id.to_string(),
+                name: id.to_string(),
+                starts: Utc::now(),
+            })
+            .collect()
+    }
+
+    // Results as the database returns them: by tournament, then by Lichess rank.
+    fn results(rows: &[(&str, &str, i32)]) -> Vec<LinkedResult> {
+        rows.iter()
+            .map(|(tournament_id, lichess_id, rank)| LinkedResult {
+                tournament_id: tournament_id.to_string(),
+                lichess_id: lichess_id.to_string(),
+                rank: *rank,
+            })
+            .collect()
+    }
+
+    fn totals(standings: &[Standing]) -> Vec<(usize, &str, i32)> {
+        standings
+            .iter()
+            .map(|standing| (standing.rank, standing.lichess_id.as_str(), standing.total))
+            .collect()
+    }
+
+    #[test]
+    fn places_among_members_ignore_unlinked_players() {
+        let standings = score(
+            &config(true, 0),
+            &tournaments(&["t1"]),
+            results(&[("t1", "alice", 3), ("t1", "bob", 7)]),
+        );
+        assert_eq!(totals(&standings), [(1, "alice", 10), (2, "bob", 6)]);
+    }
+
+    #[test]
+    fn lichess_ranks_beyond_the_table_earn_participation_points() {
+        let standings = score(
+            &config(false, 0),
+            &tournaments(&["t1"]),
+            results(&[("t1", "alice", 3), ("t1", "bob", 7)]),
+        );
+        assert_eq!(totals(&standings), [(1, "alice", 4), (2, "bob", 1)]);
+    }
+
+    #[test]
+    fn ties_share_a_rank() {
+        let standings = score(
+            &config(true, 0),
+            &tournaments(&["t1", "t2"]),
+            results(&[
+                ("t1", "bob", 1),
+                ("t1", "alice", 2),
+                ("t1", "carol", 3),
+                ("t2", "alice", 1),
+                ("t2", "bob", 2),
+            ]),
+        );
+        assert_eq!(
+            totals(&standings),
+            [(1, "alice", 16), (1, "bob", 16), (3, "carol", 4)]
+        );
+    }
+
+    #[test]
+    fn best_of_counts_only_the_best_results() {
+        let standings = score(
+            &config(true, 2),
+            &tournaments(&["t1", "t2", "t3"]),
+            results(&[
+                ("t1", "alice", 1),
+                ("t2", "bob", 1),
+                ("t2", "alice", 2),
+                ("t3", "alice", 1),
+            ]),
+        );
+        assert_eq!(totals(&standings), [(1, "alice", 20), (2, "bob", 10)]);
+        assert_eq!(standings[0].events, 3);
+        assert_eq!(standings[0].points, [Some(10), Some(6), Some(10)]);
+        assert_eq!(standings[1].points, [None, Some(10), None]);
+    }
+
+    #[test]
+    fn results_of_other_tournaments_are_ignored() {
+        let standings = score(
+            &config(true, 0),
+            &tournaments(&["t1"]),
+            results(&[("t0", "bob", 1), ("t1", "alice", 1)]),
+        );
+        assert_eq!(totals(&standings), [(1, "alice", 10)]);
+    }
+}
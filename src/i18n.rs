@@ -0,0 +1,288 @@
+use crate::config::OrgConfig;
+use crate::types::*;
+use rocket::Request;
+use rocket::http::{Cookie, CookieJar, SameSite};
+use rocket::outcome::Outcome;
+use rocket::request::FromRequest;
+use rocket::time::Duration;
+use rocket_dyn_templates::tera::{self, Function, Value};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+use toml::Table;
+
+pub const LOCALES_DIRECTORY: &str = "locales";
+const LOCALE_COOKIE: &str = "e2llang";
+
+#[derive(Serialize, Clone)]
+pub struct Language {
+    pub code: String,
+    pub name: String,
+}
+
+pub struct Catalogue {
+    default_locale: String,
+    messages: HashMap<String, HashMap<String, String>>,
+    org_params: Vec<(String, String)>,
+    languages: Vec<Language>,
+}
+
+fn flatten(prefix: &str, table: &Table, into: &mut HashMap<String, String>) {
+    for (key, value) in table {
+        let key = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        match value {
+            toml::Value::Table(inner) => flatten(&key, inner, into),
+            toml::Value::String(message) => {
+                into.insert(key, message.clone());
+            }
+            _ => {}
+        }
+    }
+}
+
+fn substitute(message: &str, params: &[(String, String)]) -> String {
+    let mut result = message.to_string();
+    for (name, value) in params {
+        result = result.replace(&format!("{{{}}}", name), value);
+    }
+    result
+}
+
+impl Catalogue {
+    pub fn load(
+        directory: &str,
+        org: &OrgConfig,
+        lichess_domain: &str,
+    ) -> Result<Catalogue, ErrorBox> {
+        let mut messages = HashMap::new();
+        for entry in
+            fs::read_dir(directory).map_err(|e| format!("Cannot read {}: {}", directory, e))?
+        {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("toml") {
+                continue;
+            }
+            let code = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .ok_or("invalid locale file name")?
+                .to_string();
+            let table: Table = toml::from_str(&fs::read_to_string(&path)?)
+                .map_err(|e| format!("Invalid {}: {}", path.display(), e))?;
+            let mut flat = HashMap::new();
+            flatten("", &table, &mut flat);
+            messages.insert(code, flat);
+        }
+
+        if !messages.contains_key(&org.default_locale) {
+            return Err(format!(
+                "org.default_locale: no catalogue {}/{}.toml",
+                directory, org.default_locale
+            )
+            .into());
+        }
+
+        let mut languages: Vec<Language> = messages
+            .iter()
+            .map(|(code, flat)| Language {
+                code: code.clone(),
+                name: flat.get("language.name").unwrap_or(code).clone(),
+            })
+            .collect();
+        languages.sort_by(|a, b| a.code.cmp(&b.code));
+
+        let org_params = [
+            ("short_name", &org.short_name),
+            ("long_name", &org.long_name),
+            ("team_id", &org.team_id),
+            ("authentication_secret", &org.authentication_secret),
+            (
+                "authentication_secret_first_word",
+                &org.authentication_secret_first_word,
+            ),
+            ("lichess_domain", &lichess_domain.to_string()),
+        ]
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+
+        Ok(Catalogue {
+            default_locale: org.default_locale.clone(),
+            messages,
+            org_params,
+            languages,
+        })
+    }
+
+    fn has_locale(&self, code: &str) -> bool {
+        self.messages.contains_key(code)
+    }
+
+    fn message<'a>(&'a self, lang: &str, key: &'a str) -> &'a str {
+        self.messages
+            .get(lang)
+            .and_then(|m| m.get(key))
+            .or_else(|| {
+                self.messages
+                    .get(&self.default_locale)
+                    .and_then(|m| m.get(key))
+            })
+            .map(|m| m.as_str())
+            .unwrap_or(key)
+    }
+
+    fn format(&self, lang: &str, key: &str, params: &[(String, String)]) -> String {
+        substitute(
+            &substitute(self.message(lang, key), params),
+            &self.org_params,
+        )
+    }
+
+    // Picks the first available language from an Accept-Language header, by quality.
+    fn negotiate(&self, accept_language: &str) -> Option<String> {
+        let mut candidates: Vec<(f32, &str)> = accept_language
+            .split(',')
+            .filter_map(|part| {
+                let mut pieces = part.trim().split(';');
+                let tag = pieces.next()?.trim();
+                let quality = pieces
+                    .find_map(|p| p.trim().strip_prefix("q="))
+                    .map_or(Some(1.0), |q| q.parse().ok())?;
+                Some((quality, tag))
+            })
+            .collect();
+        candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+        candidates.into_iter().find_map(|(_, tag)| {
+            let primary = tag.split('-').next()?.to_lowercase();
+            self.has_locale(&primary).then_some(primary)
+        })
+    }
+}
+
+pub struct TemplateFunction(pub Arc<Catalogue>);
+
+// {{ t(key="...", lang=lang, name=value) }} in templates. Arguments and organisation values are
+// escaped, the catalogue messages themselves may contain markup.
+impl Function for TemplateFunction {
+    fn call(&self, args: &HashMap<String, Value>) -> tera::Result<Value> {
+        let catalogue = &self.0;
+        let key = args
+            .get("key")
+            .and_then(Value::as_str)
+            .ok_or("t: missing key")?;
+        let lang = args
+            .get("lang")
+            .and_then(Value::as_str)
+            .unwrap_or(&catalogue.default_locale);
+        let params: Vec<(String, String)> = args
+            .iter()
+            .filter(|(name, _)| *name != "key" && *name != "lang")
+            .map(|(name, value)| {
+                let value = match value {
+                    Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                };
+                (name.clone(), tera::escape_html(&value))
+            })
+            .collect();
+        let org_params: Vec<(String, String)> = catalogue
+            .org_params
+            .iter()
+            .map(|(name, value)| (name.clone(), tera::escape_html(value)))
+            .collect();
+        Ok(Value::String(substitute(
+            &substitute(catalogue.message(lang, key), &params),
+            &org_params,
+        )))
+    }
+
+    fn is_safe(&self) -> bool {
+        true
+    }
+}
+
+pub struct Locale {
+    pub lang: String,
+    catalogue: Arc<Catalogue>,
+}
+
+impl Locale {
+    pub fn tr(&self, key: &str, params: &[(&str, &str)]) -> String {
+        let params: Vec<(String, String)> = params
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        self.catalogue.format(&self.lang, key, &params)
+    }
+
+    pub fn month(&self, month: u32) -> String {
+        self.tr(&format!("month.{}", month), &[])
+    }
+
+    pub fn date(&self, year: i32, month: u32, day: u32) -> String {
+        self.tr(
+            "date.full",
+            &[
+                ("year", &year.to_string()),
+                ("month", &self.month(month)),
+                ("day", &day.to_string()),
+            ],
+        )
+    }
+
+    pub fn day_month(&self, month: u32, day: u32) -> String {
+        self.tr(
+            "date.day_month",
+            &[("month", &self.month(month)), ("day", &day.to_string())],
+        )
+    }
+
+    pub fn languages(&self) -> Vec<Language> {
+        self.catalogue.languages.clone()
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Locale {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> rocket::request::Outcome<Self, Self::Error> {
+        let catalogue = request
+            .rocket()
+            .state::<Arc<Catalogue>>()
+            .expect("catalogue is managed")
+            .clone();
+
+        let lang = request
+            .cookies()
+            .get(LOCALE_COOKIE)
+            .map(|c| c.value().to_string())
+            .filter(|code| catalogue.has_locale(code))
+            .or_else(|| {
+                request
+                    .headers()
+                    .get_one("Accept-Language")
+                    .and_then(|header| catalogue.negotiate(header))
+            })
+            .unwrap_or_else(|| catalogue.default_locale.clone());
+
+        Outcome::Success(Locale { lang, catalogue })
+    }
+}
+
+pub fn set_locale_cookie(cookies: &CookieJar<'_>, catalogue: &Catalogue, code: &str) -> bool {
+    if !catalogue.has_locale(code) {
+        return false;
+    }
+    let mut locale_cookie = Cookie::new(LOCALE_COOKIE, code.to_string());
+    locale_cookie.set_max_age(Duration::days(365));
+    locale_cookie.set_same_site(SameSite::Lax);
+    locale_cookie.set_secure(true);
+    cookies.add(locale_cookie);
+    true
+}
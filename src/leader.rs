@@ -0,0 +1,81 @@
+use crate::db::OrgDbClient;
+use crate::randstr;
+use crate::textlog;
+use crate::types::*;
+use rocket::tokio::time::sleep;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const LEASE_NAME: &str = "background_jobs";
+
+// Whether this instance currently holds the lease for running background jobs. Leadership is only
+// trusted until the lease would have expired, so an instance that loses the database steps down
+// before another one can take over.
+#[derive(Clone)]
+pub struct Leadership(Arc<Mutex<Option<Instant>>>);
+
+impl Leadership {
+    // For one-off commands, which run regardless of any replicas.
+    pub fn always() -> Leadership {
+        Leadership(Arc::new(Mutex::new(None)))
+    }
+
+    pub fn is_leader(&self) -> bool {
+        match *self.0.lock().unwrap() {
+            Some(until) => Instant::now() < until,
+            None => true,
+        }
+    }
+
+    fn hold_until(&self, until: Instant) {
+        *self.0.lock().unwrap() = Some(until);
+    }
+}
+
+fn instance_id() -> Result<String, ErrorBox> {
+    let host = env::var("HOSTNAME").unwrap_or_else(|_| String::from("instance"));
+    Ok(format!("{}-{}", host, &randstr::random_string()?[..8]))
+}
+
+// Keeps trying to acquire or renew the lease. When the leader dies, its lease runs out after
+// `lease_seconds` and another instance takes over.
+pub fn launch(db: OrgDbClient, lease_seconds: i64) -> Result<Leadership, ErrorBox> {
+    let holder = instance_id()?;
+    let leadership = Leadership(Arc::new(Mutex::new(Some(Instant::now()))));
+    let lease = Duration::from_secs(lease_seconds as u64);
+    let renewer = leadership.clone();
+
+    rocket::tokio::task::spawn(async move {
+        let mut was_leader = false;
+        loop {
+            let started = Instant::now();
+            let is_leader = match db.acquire_lease(LEASE_NAME, &holder, lease_seconds).await {
+                Ok(acquired) => acquired,
+                Err(e) => {
+                    textlog::append_line_to(
+                        "leader.error.log",
+                        &format!("Could not renew lease: {}", e),
+                    )
+                    .unwrap_or(());
+                    println!("Could not renew lease: {}", e);
+                    false
+                }
+            };
+
+            renewer.hold_until(if is_leader { started + lease } else { started });
+            if is_leader != was_leader {
+                if is_leader {
+                    println!("{} is now running background jobs", holder);
+                } else {
+                    println!("{} stopped running background jobs", holder);
+                }
+                was_leader = is_leader;
+            }
+
+            sleep(lease / 3).await;
+        }
+    });
+
+    Ok(leadership)
+}
@@ -1,8 +1,9 @@
 use crate::types::*;
 use reqwest::header::*;
-use reqwest::{Client, Request};
+use reqwest::{Client, Request, Response, StatusCode};
 use reqwest::{Method, Url};
 use serde::Deserialize;
+use serde::de::DeserializeOwned;
 
 #[derive(Deserialize)]
 pub struct OAuthToken {
@@ -21,8 +22,95 @@ pub struct MaybeOk {
     pub ok: bool,
 }
 
+#[derive(Clone, Copy)]
+pub enum TournamentKind {
+    Arena,
+    Swiss,
+}
+
+impl TournamentKind {
+    pub fn parse(kind: &str) -> Option<TournamentKind> {
+        match kind {
+            "arena" => Some(TournamentKind::Arena),
+            "swiss" => Some(TournamentKind::Swiss),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TournamentKind::Arena => "arena",
+            TournamentKind::Swiss => "swiss",
+        }
+    }
+
+    fn api_path(&self) -> &'static str {
+        match self {
+            TournamentKind::Arena => "tournament",
+            TournamentKind::Swiss => "swiss",
+        }
+    }
+}
+
+// A line of the results of an arena (which has a `score`) or a swiss (which has `points`).
+#[derive(Deserialize)]
+pub struct TournamentResult {
+    pub rank: u32,
+    pub username: String,
+    pub score: Option<f64>,
+    pub points: Option<f64>,
+}
+
+#[derive(Deserialize)]
+pub struct GameUser {
+    pub id: String,
+}
+
+#[derive(Deserialize)]
+pub struct GamePlayer {
+    pub user: Option<GameUser>,
+}
+
+#[derive(Deserialize)]
+pub struct GamePlayers {
+    pub white: GamePlayer,
+    pub black: GamePlayer,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Game {
+    pub id: String,
+    pub created_at: i64,
+    pub status: String,
+    pub winner: Option<String>,
+    pub players: GamePlayers,
+}
+
+// An arena from the list of a team's tournaments.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TeamArena {
+    pub id: String,
+    pub full_name: String,
+    pub starts_at: i64,
+    pub finishes_at: i64,
+}
+
+// A single arena, where `startsAt` is an ISO 8601 string rather than a timestamp.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Arena {
+    pub id: String,
+    pub full_name: String,
+    pub starts_at: String,
+    #[serde(default)]
+    pub is_finished: bool,
+}
+
 fn create_request(
     method: Method,
+    lichess_domain: &str,
     url: String,
     accept: &str,
     authorization: String,
@@ -31,7 +119,7 @@ fn create_request(
     let headers = req.headers_mut();
     headers.insert(ACCEPT, accept.parse()?);
     headers.insert(AUTHORIZATION, authorization.parse()?);
-    headers.insert(ORIGIN, "https://lichess.org".parse()?);
+    headers.insert(ORIGIN, format!("https://{}", lichess_domain).parse()?);
     Ok(req)
 }
 
@@ -42,6 +130,7 @@ pub async fn get_user(
 ) -> Result<User, ErrorBox> {
     let req = create_request(
         Method::GET,
+        lichess_domain,
         format!("https://{}/api/account", lichess_domain),
         "application/json",
         format!("{} {}", token.token_type, token.access_token),
@@ -53,11 +142,15 @@ pub async fn get_user(
 pub async fn oauth_token_from_code(
     code: &str,
     http_client: &Client,
+    lichess_domain: &str,
     client_id: &str,
     code_verifier: &str,
     redirect_uri: &str,
 ) -> Result<OAuthToken, Box<dyn std::error::Error>> {
-    let mut req = Request::new(Method::POST, Url::parse("https://lichess.org/api/token")?);
+    let mut req = Request::new(
+        Method::POST,
+        Url::parse(&format!("https://{}/api/token", lichess_domain))?,
+    );
     let body = req.body_mut();
     *body = Some(
         format!(
@@ -86,6 +179,7 @@ async fn try_join_team(
 ) -> Result<bool, ErrorBox> {
     let mut req = create_request(
         Method::POST,
+        lichess_domain,
         format!("https://{}/team/{}/join", lichess_domain, team_id),
         "application/json",
         format!("Bearer {}", token),
@@ -119,6 +213,7 @@ pub async fn try_kick_from_team(
 ) -> Result<bool, ErrorBox> {
     let req = create_request(
         Method::POST,
+        lichess_domain,
         format!(
             "https://{}/team/{}/kick/{}",
             lichess_domain, team_id, user_id
@@ -130,14 +225,164 @@ pub async fn try_kick_from_team(
     Ok(response.ok)
 }
 
-pub async fn kick_from_team(
+pub enum KickOutcome {
+    Kicked,
+    RateLimited(Option<u64>),
+    Failed(String),
+}
+
+fn retry_after_seconds(response: &Response) -> Option<u64> {
+    response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+}
+
+// Tells a rate limit (with its Retry-After) apart from other failures, unlike try_kick_from_team.
+pub async fn kick_from_team_outcome(
     http_client: &Client,
     token: &str,
     lichess_domain: &str,
     team_id: &str,
     user_id: &str,
-) -> bool {
-    try_kick_from_team(http_client, token, lichess_domain, team_id, user_id)
-        .await
-        .unwrap_or(false)
+) -> KickOutcome {
+    let req = match create_request(
+        Method::POST,
+        lichess_domain,
+        format!(
+            "https://{}/team/{}/kick/{}",
+            lichess_domain, team_id, user_id
+        ),
+        "application/json",
+        format!("Bearer {}", token),
+    ) {
+        Ok(req) => req,
+        Err(e) => return KickOutcome::Failed(e.to_string()),
+    };
+    let response = match http_client.execute(req).await {
+        Ok(response) => response,
+        Err(e) => return KickOutcome::Failed(e.to_string()),
+    };
+    if response.status() == StatusCode::TOO_MANY_REQUESTS {
+        return KickOutcome::RateLimited(retry_after_seconds(&response));
+    }
+    let status = response.status();
+    match response.json::<MaybeOk>().await {
+        Ok(MaybeOk { ok: true }) => KickOutcome::Kicked,
+        Ok(_) => KickOutcome::Failed(format!("Lichess refused the kick ({})", status)),
+        Err(e) => KickOutcome::Failed(format!("{} ({})", e, status)),
+    }
+}
+
+// Fetches a newline-delimited JSON export, or None if Lichess does not know `url`.
+async fn get_ndjson<T: DeserializeOwned>(
+    http_client: &Client,
+    token: &str,
+    lichess_domain: &str,
+    url: String,
+) -> Result<Option<Vec<T>>, ErrorBox> {
+    let req = create_request(
+        Method::GET,
+        lichess_domain,
+        url,
+        "application/x-ndjson",
+        format!("Bearer {}", token),
+    )?;
+    let response = http_client.execute(req).await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let body = response.error_for_status()?.text().await?;
+    let mut items = vec![];
+    for line in body.lines().filter(|line| !line.trim().is_empty()) {
+        items.push(serde_json::from_str(line)?);
+    }
+    Ok(Some(items))
+}
+
+pub async fn get_tournament_results(
+    http_client: &Client,
+    token: &str,
+    lichess_domain: &str,
+    kind: TournamentKind,
+    tournament_id: &str,
+) -> Result<Option<Vec<TournamentResult>>, ErrorBox> {
+    get_ndjson(
+        http_client,
+        token,
+        lichess_domain,
+        format!(
+            "https://{}/api/{}/{}/results",
+            lichess_domain,
+            kind.api_path(),
+            tournament_id
+        ),
+    )
+    .await
+}
+
+pub async fn get_tournament_games(
+    http_client: &Client,
+    token: &str,
+    lichess_domain: &str,
+    kind: TournamentKind,
+    tournament_id: &str,
+) -> Result<Option<Vec<Game>>, ErrorBox> {
+    get_ndjson(
+        http_client,
+        token,
+        lichess_domain,
+        format!(
+            "https://{}/api/{}/{}/games?moves=false",
+            lichess_domain,
+            kind.api_path(),
+            tournament_id
+        ),
+    )
+    .await
+}
+
+pub async fn get_arena(
+    http_client: &Client,
+    token: &str,
+    lichess_domain: &str,
+    tournament_id: &str,
+) -> Result<Option<Arena>, ErrorBox> {
+    let req = create_request(
+        Method::GET,
+        lichess_domain,
+        format!(
+            "https://{}/api/tournament/{}",
+            lichess_domain, tournament_id
+        ),
+        "application/json",
+        format!("Bearer {}", token),
+    )?;
+    let response = http_client.execute(req).await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    Ok(Some(response.error_for_status()?.json().await?))
+}
+
+// The team's most recent `max` arenas, newest first.
+pub async fn get_team_arenas(
+    http_client: &Client,
+    token: &str,
+    lichess_domain: &str,
+    team_id: &str,
+    max: u32,
+) -> Result<Vec<TeamArena>, ErrorBox> {
+    get_ndjson(
+        http_client,
+        token,
+        lichess_domain,
+        format!(
+            "https://{}/api/team/{}/arena?max={}",
+            lichess_domain, team_id, max
+        ),
+    )
+    .await?
+    .ok_or_else(|| format!("Lichess does not know the team {}", team_id).into())
 }
@@ -0,0 +1,131 @@
+use crate::config::LinkLimitConfig;
+use crate::db::OrgDbClient;
+use crate::retry;
+use crate::types::*;
+use chrono::{DateTime, Utc};
+
+pub const LICHESS_ACCOUNT: &str = "lichess";
+pub const ORG_ID: &str = "org";
+
+pub fn is_valid_kind(kind: &str) -> bool {
+    kind == LICHESS_ACCOUNT || kind == ORG_ID
+}
+
+fn lockout_seconds(config: &LinkLimitConfig, failures: i32) -> Option<i64> {
+    let over = failures - config.free_attempts;
+    if over < 0 {
+        return None;
+    }
+    Some(retry::backoff_delay(
+        over + 1,
+        config.base_lockout_seconds,
+        config.max_lockout_seconds,
+    ))
+}
+
+// The lockouts after each failure from the `free_attempts`th on, up to the first one that reaches
+// the cap.
+fn lockouts(config: &LinkLimitConfig) -> Vec<i64> {
+    let mut lockouts = vec![];
+    for failures in config.free_attempts.. {
+        let Some(seconds) = lockout_seconds(config, failures) else {
+            break;
+        };
+        lockouts.push(seconds);
+        if seconds >= config.max_lockout_seconds || lockouts.len() > 62 {
+            break;
+        }
+    }
+    lockouts
+}
+
+async fn reserve_for(
+    db: &OrgDbClient,
+    config: &LinkLimitConfig,
+    kind: &str,
+    subject: &str,
+) -> Result<Option<DateTime<Utc>>, ErrorBox> {
+    if let Some(failures) = db
+        .reserve_link_attempt(
+            kind,
+            subject,
+            config.free_attempts,
+            &lockouts(config),
+            config.forget_after_seconds,
+        )
+        .await?
+    {
+        if failures >= config.free_attempts {
+            println!(
+                "Locked out linking attempts for {} {} after {} attempts",
+                kind, subject, failures
+            );
+        }
+        return Ok(None);
+    }
+    Ok(Some(
+        db.get_link_lockout(kind, subject)
+            .await?
+            .unwrap_or_else(Utc::now),
+    ))
+}
+
+// Counts a linking attempt as failed before the membership system is asked, so that concurrent
+// attempts cannot get past the lockout, and returns when the lockout ends if the Lichess account
+// or member ID is locked out. A successful attempt is cleared again.
+pub async fn reserve(
+    db: &OrgDbClient,
+    config: &LinkLimitConfig,
+    lichess_id: &str,
+    org_id: &str,
+) -> Result<Option<DateTime<Utc>>, ErrorBox> {
+    let by_account = reserve_for(db, config, LICHESS_ACCOUNT, lichess_id).await?;
+    let by_org_id = reserve_for(db, config, ORG_ID, org_id).await?;
+    Ok(by_account.max(by_org_id))
+}
+
+pub async fn clear(db: &OrgDbClient, lichess_id: &str, org_id: &str) -> Result<(), ErrorBox> {
+    db.clear_link_attempts(LICHESS_ACCOUNT, lichess_id).await?;
+    db.clear_link_attempts(ORG_ID, org_id).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> LinkLimitConfig {
+        LinkLimitConfig {
+            free_attempts: 3,
+            base_lockout_seconds: 60,
+            max_lockout_seconds: 600,
+            forget_after_seconds: 86400,
+        }
+    }
+
+    #[test]
+    fn free_attempts_are_not_locked_out() {
+        for failures in 0..3 {
+            assert_eq!(lockout_seconds(&config(), failures), None);
+        }
+    }
+
+    #[test]
+    fn lockout_doubles_with_each_further_failure() {
+        assert_eq!(lockout_seconds(&config(), 3), Some(60));
+        assert_eq!(lockout_seconds(&config(), 4), Some(120));
+        assert_eq!(lockout_seconds(&config(), 5), Some(240));
+        assert_eq!(lockout_seconds(&config(), 6), Some(480));
+    }
+
+    #[test]
+    fn lockout_is_capped() {
+        assert_eq!(lockout_seconds(&config(), 7), Some(600));
+        assert_eq!(lockout_seconds(&config(), 1000), Some(600));
+    }
+
+    #[test]
+    fn lockouts_run_from_the_first_lockout_to_the_cap() {
+        assert_eq!(lockouts(&config()), [60, 120, 240, 480, 600]);
+    }
+}
@@ -1,34 +1,57 @@
 use base64::Engine;
+use chrono::Datelike;
 use rocket::form::Form;
-use rocket::http::{CookieJar, Status};
+use rocket::http::{ContentType, CookieJar, Header, Status};
 use rocket::response::{Redirect, status};
 use rocket::serde::json::Json;
-use rocket::{FromForm, State, get, launch, post, routes, uri};
+use rocket::{Build, FromForm, Responder, Rocket, State, get, post, routes, uri};
 use rocket_dyn_templates::Template;
 use serde_json::json;
 use std::collections::HashMap;
-use std::fs;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
+mod announce;
+mod api;
+mod apikey;
 mod azolve;
+mod cli;
 mod config;
 mod db;
 mod expwatch;
+mod grandprix;
+mod i18n;
+mod leader;
 mod lichess;
+mod linklimit;
+mod memberhook;
 mod org;
+mod personaldata;
 mod randstr;
+mod retention;
+mod retry;
+mod reverify;
+mod schedule;
 mod session;
 mod tempctx;
 mod textlog;
+mod tournament;
 mod types;
+mod verifier;
+mod webhooks;
+mod writeback;
 
 use base64::engine::general_purpose::STANDARD as BASE64;
 use config::Config;
-use db::OrgDbClient;
+use db::{Membership, OrgDbClient, Registration};
+use i18n::{Catalogue, Locale};
+use lichess::{KickOutcome, TournamentKind};
 use randstr::random_string;
-use session::Session;
+use session::{PendingTransfer, Session};
 use sha2::{Digest, Sha256};
 use tempctx::*;
 use types::*;
+use verifier::Verification;
 
 type ErrorStatus = status::Custom<&'static str>;
 
@@ -38,8 +61,8 @@ fn to_500(e: ErrorBox) -> ErrorStatus {
 }
 
 #[get("/", rank = 2)]
-async fn index(config: &State<Config>) -> Template {
-    Template::render("index", &empty_context(&config))
+async fn index(config: &State<Config>, locale: Locale) -> Template {
+    Template::render("index", empty_context(config, &locale))
 }
 
 #[get("/auth")]
@@ -59,10 +82,11 @@ async fn auth(config: &State<Config>, cookies: &CookieJar<'_>) -> Result<Redirec
         .replace("/", "_");
 
     let url = format!(
-        "https://lichess.org/oauth?response_type=code\
+        "https://{}/oauth?response_type=code\
             &client_id={}&scope=team:write\
             &redirect_uri={}%2Foauth_redirect\
             &state={}&code_challenge_method=S256&code_challenge={}",
+        config.lichess.domain,
         urlencoding::encode(&config.lichess.client_id),
         urlencoding::encode(&config.server.url),
         oauth_state,
@@ -78,7 +102,9 @@ async fn oauth_redirect(
     code: String,
     state: String,
     config: &State<Config>,
+    db: &State<OrgDbClient>,
     http_client: &State<reqwest::Client>,
+    locale: Locale,
 ) -> Result<Result<Template, Status>, ErrorStatus> {
     match (
         session::pop_oauth_state(cookies).map(|v| v == state),
@@ -87,16 +113,22 @@ async fn oauth_redirect(
         (Some(true), Some(code_verifier)) => {
             let token = lichess::oauth_token_from_code(
                 &code,
-                &http_client,
+                http_client,
+                &config.lichess.domain,
                 &config.lichess.client_id,
                 &code_verifier,
                 &format!("{}/oauth_redirect", config.server.url),
             )
             .await
             .unwrap();
-            let user = lichess::get_user(&token, &http_client, "lichess.org")
+            let user = lichess::get_user(&token, http_client, &config.lichess.domain)
                 .await
                 .unwrap();
+            if let Some(visitor) = session::pop_referral_visitor(cookies) {
+                db.claim_anonymous_referrals(&visitor, &user.id)
+                    .await
+                    .map_err(to_500)?;
+            }
             session::set_session(
                 cookies,
                 Session {
@@ -106,7 +138,10 @@ async fn oauth_redirect(
                 },
             )
             .map_err(to_500)?;
-            Ok(Ok(Template::render("redirect", &empty_context(&config))))
+            Ok(Ok(Template::render(
+                "redirect",
+                empty_context(config, &locale),
+            )))
         }
         _ => Ok(Err(Status::BadRequest)),
     }
@@ -117,25 +152,45 @@ async fn manage_authed(
     session: Session,
     config: &State<Config>,
     db: &State<OrgDbClient>,
+    locale: Locale,
 ) -> Result<Template, ErrorStatus> {
-    let logged_in = make_logged_in_context(&session, &config);
+    let logged_in = make_logged_in_context(&session, config, &locale);
 
     match db
         .get_member_for_lichess_id(&session.lichess_id)
         .await
         .map_err(to_500)?
     {
-        Some(member) => Ok(Template::render(
-            "linked",
-            make_linked_context(
-                logged_in,
-                member.org_id,
-                member.exp_year,
-                can_use_form(&session, &config, &db).await.map_err(to_500)?,
-                &config.expiry,
-            ),
-        )),
-        None => Ok(Template::render("notlinked", logged_in)),
+        Some(member) => {
+            let timezone = org::timezone_from_string(&config.org.timezone).map_err(to_500)?;
+            Ok(Template::render(
+                "linked",
+                make_linked_context(
+                    logged_in,
+                    member.org_id,
+                    member.exp_year,
+                    org::membership_state(
+                        member.exp_year,
+                        timezone,
+                        config.expiry.membership_month,
+                        config.expiry.membership_day,
+                    ),
+                    can_use_form(&session, config, db).await.map_err(to_500)?,
+                    &config.expiry,
+                    &locale,
+                ),
+            ))
+        }
+        None => {
+            let previous = db
+                .get_last_past_membership_for_lichess_id(&session.lichess_id)
+                .await
+                .map_err(to_500)?;
+            Ok(Template::render(
+                "notlinked",
+                make_not_linked_context(logged_in, previous),
+            ))
+        }
     }
 }
 
@@ -148,12 +203,14 @@ async fn can_use_form(
     db.get_member_for_lichess_id(&session.lichess_id)
         .await
         .map(|maybe_member| match maybe_member {
+            // A membership with an impossible expiry year can be linked again to correct it.
             Some(member) => org::is_past_expiry(
                 member.exp_year,
                 timezone,
                 config.expiry.membership_month,
                 config.expiry.membership_day,
-            ),
+            )
+            .unwrap_or(true),
             None => true,
         })
 }
@@ -163,13 +220,14 @@ async fn show_form(
     session: Session,
     config: &State<Config>,
     db: &State<OrgDbClient>,
+    locale: Locale,
 ) -> Result<Result<Template, Redirect>, ErrorStatus> {
-    if !can_use_form(&session, &config, &db).await.map_err(to_500)? {
+    if !can_use_form(&session, config, db).await.map_err(to_500)? {
         Ok(Err(Redirect::to(uri!(index))))
     } else {
         Ok(Ok(Template::render(
             "form",
-            make_error_context(make_logged_in_context(&session, &config), ""),
+            make_error_context(make_logged_in_context(&session, config, &locale), ""),
         )))
     }
 }
@@ -179,15 +237,15 @@ async fn form_redirect_index() -> Redirect {
     Redirect::to(uri!(index))
 }
 
-async fn org_id_unused(
+async fn org_id_linked_elsewhere(
     org_id: &str,
     session: &Session,
     db: &State<OrgDbClient>,
-) -> Result<bool, ErrorBox> {
-    match db.get_member_for_org_id(&org_id).await? {
-        Some(member) => Ok(session.lichess_id == member.lichess_id),
-        None => Ok(true),
-    }
+) -> Result<Option<Membership>, ErrorBox> {
+    Ok(db
+        .get_member_for_org_id(org_id)
+        .await?
+        .filter(|member| session.lichess_id != member.lichess_id))
 }
 
 #[derive(FromForm)]
@@ -200,115 +258,485 @@ struct OrgInfo {
 async fn link_memberships(
     form: Option<Form<OrgInfo>>,
     session: Session,
+    cookies: &CookieJar<'_>,
     config: &State<Config>,
     db: &State<OrgDbClient>,
     http_client: &State<reqwest::Client>,
+    locale: Locale,
 ) -> Result<Result<Redirect, Template>, ErrorStatus> {
-    if !can_use_form(&session, &config, &db).await.map_err(to_500)? {
+    if !can_use_form(&session, config, db).await.map_err(to_500)? {
         return Ok(Ok(Redirect::to(uri!(index))));
     }
 
-    let logged_in = make_logged_in_context(&session, &config);
+    let logged_in = make_logged_in_context(&session, config, &locale);
 
     let timezone = org::timezone_from_string(&config.org.timezone).map_err(to_500)?;
 
     Ok(match form {
         Some(org_info) => {
-            match azolve::verify_user(
-                &http_client,
+            if let Some(until) = linklimit::reserve(
+                db,
+                &config.link_limit,
+                &session.lichess_id,
                 &org_info.org_id,
-                &org_info.org_password,
-                &config.azolve.api,
-                &config.azolve.api_pwd,
-                &config.azolve.api_token,
-                &config.org.authentication_secret,
-                &config.azolve.test_backdoor_member_id,
-                &config.azolve.test_backdoor_password,
             )
             .await
+            .map_err(to_500)?
             {
-                Ok(true) => {
-                    if org_id_unused(&org_info.org_id, &session, &db)
+                return Ok(Err(Template::render(
+                    "form",
+                    make_error_context(logged_in, &{
+                        let until = until.with_timezone(&timezone);
+                        locale.tr(
+                            "error.locked_out",
+                            &[
+                                ("time", &until.format("%H:%M").to_string()),
+                                ("date", &locale.day_month(until.month(), until.day())),
+                            ],
+                        )
+                    }),
+                )));
+            }
+
+            let verification = verifier::verify_user(
+                db,
+                http_client,
+                config,
+                &session.lichess_id,
+                &org_info.org_id,
+                &org_info.org_password,
+            )
+            .await;
+
+            if let Ok(Verification::Verified | Verification::VerifiedInTestMode) = verification {
+                linklimit::clear(db, &session.lichess_id, &org_info.org_id)
+                    .await
+                    .map_err(to_500)?;
+            }
+
+            match verification {
+                Ok(Verification::Verified | Verification::VerifiedInTestMode) => {
+                    if let Some(existing) = org_id_linked_elsewhere(&org_info.org_id, &session, db)
                         .await
                         .map_err(to_500)?
                     {
-                        if lichess::join_team(
-                            &http_client,
-                            &session.oauth_token,
-                            "lichess.org",
-                            &config.org.team_id,
-                            &config.lichess.team_password,
+                        session::set_pending_transfer(
+                            cookies,
+                            PendingTransfer {
+                                lichess_id: session.lichess_id.clone(),
+                                org_id: org_info.org_id.clone(),
+                            },
                         )
-                        .await
+                        .map_err(to_500)?;
+                        Err(Template::render(
+                            "transfer",
+                            make_transfer_context(
+                                logged_in,
+                                org_info.org_id.clone(),
+                                existing.lichess_id,
+                            ),
+                        ))
+                    } else {
+                        // The team is joined while the registration transaction is open, so a
+                        // failed join writes nothing and a conflicting link rolls back.
+                        let membership = Membership {
+                            org_id: org_info.org_id.clone(),
+                            lichess_id: session.lichess_id.clone(),
+                            exp_year: org::new_membership_exp_year(
+                                timezone,
+                                config.expiry.membership_month,
+                                config.expiry.membership_day,
+                            ),
+                        };
+                        let current = db
+                            .get_member_for_lichess_id(&session.lichess_id)
+                            .await
+                            .map_err(to_500)?;
+                        let renewing = current
+                            .as_ref()
+                            .is_some_and(|current| current.org_id == membership.org_id);
+                        let joined = AtomicBool::new(false);
+                        let registration = db
+                            .register_member_with(
+                                &membership.org_id,
+                                &membership.lichess_id,
+                                membership.exp_year,
+                                async {
+                                    let ok = lichess::join_team(
+                                        http_client,
+                                        &session.oauth_token,
+                                        &config.lichess.domain,
+                                        &config.org.team_id,
+                                        &config.lichess.team_password,
+                                    )
+                                    .await;
+                                    joined.store(ok, Ordering::Relaxed);
+                                    ok
+                                },
+                            )
+                            .await;
+                        // If the commit failed after the team was joined, the account would be in
+                        // the team without a link, so it is removed again. Accounts that were
+                        // already linked stay, as their previous link is still in place.
+                        if !matches!(registration, Ok(Registration::Registered))
+                            && joined.load(Ordering::Relaxed)
+                            && current.is_none()
                         {
-                            db.register_member(
-                                &org_info.org_id,
+                            lichess::try_kick_from_team(
+                                http_client,
+                                &config.lichess.personal_api_token,
+                                &config.lichess.domain,
+                                &config.org.team_id,
                                 &session.lichess_id,
-                                org::current_year(timezone)
-                                    + (if org::is_past_expiry_this_year(
-                                        timezone,
-                                        config.expiry.membership_month,
-                                        config.expiry.membership_day,
-                                    ) {
-                                        1
-                                    } else {
-                                        0
-                                    }),
                             )
                             .await
-                            .map_err(to_500)?;
-                            Ok(Redirect::to(uri!(index)))
-                        } else {
-                            Err(Template::render(
+                            .unwrap_or_else(|e| {
+                                println!(
+                                    "Could not remove {} from the team after a failed link: {}",
+                                    &session.lichess_id, e
+                                );
+                                false
+                            });
+                        }
+                        let registration = registration.map_err(to_500)?;
+                        match registration {
+                            Registration::Registered => {
+                                db.mark_referral_converted(&session.lichess_id)
+                                    .await
+                                    .map_err(to_500)?;
+                                announce::linked(
+                                    db,
+                                    &config.webhooks,
+                                    &config.azolve,
+                                    &membership,
+                                    renewing,
+                                )
+                                .await
+                                .map_err(to_500)?;
+                                Ok(Redirect::to(uri!(index)))
+                            }
+                            Registration::AlreadyLinked => Err(Template::render(
                                 "form",
                                 make_error_context(
                                     logged_in,
-                                    "Could not add you to the Lichess team, please try again later.",
+                                    &locale.tr("error.already_linked", &[]),
                                 ),
-                            ))
+                            )),
+                            Registration::Cancelled => Err(Template::render(
+                                "form",
+                                make_error_context(logged_in, &locale.tr("error.join_team", &[])),
+                            )),
                         }
-                    } else {
-                        Err(Template::render(
-                            "form",
-                            make_error_context(
-                                logged_in,
-                                "This membership is already linked to a Lichess account.",
-                            ),
-                        ))
                     }
                 }
-                Ok(false) => Err(Template::render(
+                Ok(Verification::Rejected) => Err(Template::render(
                     "form",
-                    make_error_context(
-                        logged_in,
-                        "Membership verification failed, please check your member ID and password.",
-                    ),
+                    make_error_context(logged_in, &locale.tr("error.verification_failed", &[])),
                 )),
                 _ => Err(Template::render(
                     "form",
-                    make_error_context(
-                        logged_in,
-                        "At the moment we're unable to verify your membership. Please try again later.",
-                    ),
+                    make_error_context(logged_in, &locale.tr("error.unavailable", &[])),
                 )),
             }
         }
         None => Err(Template::render(
             "form",
-            make_error_context(logged_in, "Invalid form data."),
+            make_error_context(logged_in, &locale.tr("error.invalid_form", &[])),
         )),
     })
 }
 
+// Moves a membership that was verified on /link from the account it is linked to, to the account
+// of the current session. The old account is removed from the team.
+#[post("/link/transfer")]
+async fn transfer_membership(
+    session: Session,
+    cookies: &CookieJar<'_>,
+    config: &State<Config>,
+    db: &State<OrgDbClient>,
+    http_client: &State<reqwest::Client>,
+    locale: Locale,
+) -> Result<Result<Redirect, Template>, ErrorStatus> {
+    let Some(pending) = session::pop_pending_transfer(cookies)
+        .filter(|pending| pending.lichess_id == session.lichess_id)
+    else {
+        return Ok(Ok(Redirect::to(uri!(show_form))));
+    };
+    let Some(existing) = org_id_linked_elsewhere(&pending.org_id, &session, db)
+        .await
+        .map_err(to_500)?
+    else {
+        return Ok(Ok(Redirect::to(uri!(index))));
+    };
+
+    let logged_in = make_logged_in_context(&session, config, &locale);
+
+    let timezone = org::timezone_from_string(&config.org.timezone).map_err(to_500)?;
+    let membership = Membership {
+        org_id: pending.org_id.clone(),
+        lichess_id: session.lichess_id.clone(),
+        exp_year: org::new_membership_exp_year(
+            timezone,
+            config.expiry.membership_month,
+            config.expiry.membership_day,
+        ),
+    };
+    let current = db
+        .get_member_for_lichess_id(&session.lichess_id)
+        .await
+        .map_err(to_500)?;
+    let joined = AtomicBool::new(false);
+    let transfer = db
+        .transfer_membership(
+            &membership.org_id,
+            &existing.lichess_id,
+            &membership.lichess_id,
+            membership.exp_year,
+            async {
+                let ok = lichess::join_team(
+                    http_client,
+                    &session.oauth_token,
+                    &config.lichess.domain,
+                    &config.org.team_id,
+                    &config.lichess.team_password,
+                )
+                .await;
+                joined.store(ok, Ordering::Relaxed);
+                ok
+            },
+        )
+        .await;
+    // As when linking, an account that joined the team for a transfer that was not kept is removed
+    // again, unless it was already linked before.
+    if !matches!(transfer, Ok(Registration::Registered))
+        && joined.load(Ordering::Relaxed)
+        && current.is_none()
+    {
+        lichess::try_kick_from_team(
+            http_client,
+            &config.lichess.personal_api_token,
+            &config.lichess.domain,
+            &config.org.team_id,
+            &session.lichess_id,
+        )
+        .await
+        .unwrap_or_else(|e| {
+            println!(
+                "Could not remove {} from the team after a failed transfer: {}",
+                &session.lichess_id, e
+            );
+            false
+        });
+    }
+    match transfer.map_err(to_500)? {
+        Registration::Registered => {}
+        Registration::AlreadyLinked => {
+            return Ok(Err(Template::render(
+                "form",
+                make_error_context(logged_in, &locale.tr("error.already_linked", &[])),
+            )));
+        }
+        Registration::Cancelled => {
+            return Ok(Err(Template::render(
+                "form",
+                make_error_context(logged_in, &locale.tr("error.join_team", &[])),
+            )));
+        }
+    }
+
+    let kicked = match lichess::kick_from_team_outcome(
+        http_client,
+        &config.lichess.personal_api_token,
+        &config.lichess.domain,
+        &config.org.team_id,
+        &existing.lichess_id,
+    )
+    .await
+    {
+        KickOutcome::Kicked => true,
+        _ => {
+            textlog::append_line_to(
+                "kick.error.log",
+                &format!(
+                    "Could not kick {} after transferring {}",
+                    &existing.lichess_id, &pending.org_id
+                ),
+            )
+            .unwrap_or(());
+            false
+        }
+    };
+
+    println!(
+        "Transferred {} from {} to {}",
+        &pending.org_id, &existing.lichess_id, &session.lichess_id
+    );
+    db.audit(
+        "transfer",
+        Some(&session.lichess_id),
+        Some(&pending.org_id),
+        &format!("from={} kicked_old={}", &existing.lichess_id, kicked),
+    )
+    .await
+    .map_err(to_500)?;
+    db.mark_referral_converted(&session.lichess_id)
+        .await
+        .map_err(to_500)?;
+    webhooks::notify(
+        db,
+        &config.webhooks,
+        webhooks::TRANSFERRED,
+        &membership,
+        Some(&existing.lichess_id),
+    )
+    .await
+    .map_err(to_500)?;
+    writeback::enqueue(
+        db,
+        &config.azolve,
+        &membership.org_id,
+        &membership.lichess_id,
+    )
+    .await
+    .map_err(to_500)?;
+
+    Ok(Ok(Redirect::to(uri!(index))))
+}
+
 #[post("/link", rank = 2)]
 async fn try_link_unauthenticated() -> Redirect {
     Redirect::to(uri!(index))
 }
 
+#[post("/link/transfer", rank = 2)]
+async fn transfer_unauthenticated() -> Redirect {
+    Redirect::to(uri!(index))
+}
+
+#[get("/unlink")]
+async fn show_unlink(
+    session: Session,
+    config: &State<Config>,
+    db: &State<OrgDbClient>,
+    locale: Locale,
+) -> Result<Result<Template, Redirect>, ErrorStatus> {
+    match db
+        .get_member_for_lichess_id(&session.lichess_id)
+        .await
+        .map_err(to_500)?
+    {
+        Some(member) => Ok(Ok(Template::render(
+            "unlink",
+            make_unlink_context(
+                make_logged_in_context(&session, config, &locale),
+                member.org_id,
+            ),
+        ))),
+        None => Ok(Err(Redirect::to(uri!(index)))),
+    }
+}
+
+#[get("/unlink", rank = 2)]
+async fn unlink_redirect_index() -> Redirect {
+    Redirect::to(uri!(index))
+}
+
+#[derive(FromForm)]
+struct UnlinkOptions {
+    leave_team: bool,
+}
+
+#[post("/unlink", data = "<form>")]
+async fn unlink(
+    session: Session,
+    config: &State<Config>,
+    db: &State<OrgDbClient>,
+    http_client: &State<reqwest::Client>,
+    form: Form<UnlinkOptions>,
+) -> Result<Redirect, ErrorStatus> {
+    let erased = db.erase_member(&session.lichess_id).await.map_err(to_500)?;
+    // From here on, nothing may be stored that points to the account: the audit row has no
+    // identifier, and the unlinked event and write-back only carry the member ID.
+
+    let left_team = form.leave_team
+        && lichess::try_kick_from_team(
+            http_client,
+            &config.lichess.personal_api_token,
+            &config.lichess.domain,
+            &config.org.team_id,
+            &session.lichess_id,
+        )
+        .await
+        .unwrap_or_else(|e| {
+            println!("Could not remove an unlinked account from the team: {}", e);
+            false
+        });
+
+    println!("A member unlinked their account");
+    db.audit(
+        "self_unlink",
+        None,
+        None,
+        &format!("was_linked={} left_team={}", erased.is_some(), left_team),
+    )
+    .await
+    .map_err(to_500)?;
+    if let Some(membership) = erased {
+        let membership = Membership {
+            lichess_id: String::new(),
+            ..membership
+        };
+        announce::ended(
+            db,
+            &config.webhooks,
+            &config.azolve,
+            webhooks::UNLINKED,
+            &membership,
+        )
+        .await
+        .map_err(to_500)?;
+    }
+
+    Ok(Redirect::to(uri!(index)))
+}
+
+#[post("/unlink", rank = 2)]
+async fn unlink_unauthenticated() -> Redirect {
+    Redirect::to(uri!(index))
+}
+
+#[derive(Responder)]
+struct Download<T> {
+    inner: T,
+    disposition: Header<'static>,
+}
+
+#[get("/me/export")]
+async fn export_personal_data(
+    session: Session,
+    db: &State<OrgDbClient>,
+) -> Result<Download<Json<personaldata::PersonalData>>, ErrorStatus> {
+    let data = personaldata::collect(db, &session.lichess_id)
+        .await
+        .map_err(to_500)?;
+    Ok(Download {
+        inner: Json(data),
+        disposition: Header::new(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}-data.json\"", &session.lichess_id),
+        ),
+    })
+}
+
+#[get("/me/export", rank = 2)]
+async fn export_redirect_index() -> Redirect {
+    Redirect::to(uri!(index))
+}
+
 #[post("/logout")]
-async fn logout(cookies: &CookieJar<'_>, config: &State<Config>) -> Template {
+async fn logout(cookies: &CookieJar<'_>, config: &State<Config>, locale: Locale) -> Template {
     session::remove_session(cookies);
-    Template::render("redirect", &empty_context(&config))
+    Template::render("redirect", empty_context(config, &locale))
 }
 
 #[get("/admin")]
@@ -316,15 +744,44 @@ async fn admin(
     session: Session,
     config: &State<Config>,
     db: &State<OrgDbClient>,
+    locale: Locale,
 ) -> Result<Result<Template, Status>, ErrorStatus> {
-    let logged_in = make_logged_in_context(&session, &config);
+    let logged_in = make_logged_in_context(&session, config, &locale);
 
     if logged_in.admin {
-        let members = db.get_members().await.map_err(to_500)?;
-        let ref_count = db.referral_count().await.map_err(to_500)?;
+        let timezone = org::timezone_from_string(&config.org.timezone).map_err(to_500)?;
+        let members = db
+            .get_members()
+            .await
+            .map_err(to_500)?
+            .into_iter()
+            .map(|membership| make_member_row(membership, timezone, &config.expiry))
+            .collect();
+        let history = db.get_membership_history().await.map_err(to_500)?;
+        let referrals = make_referral_chart(
+            db.referral_count().await.map_err(to_500)?,
+            db.referral_stats_by_month(&config.org.timezone)
+                .await
+                .map_err(to_500)?,
+            db.referral_stats_by_source().await.map_err(to_500)?,
+        );
+        let lockouts = db.get_link_lockouts().await.map_err(to_500)?;
+        let failed_kicks = db
+            .get_kicks_in_state(db::KICK_FAILED)
+            .await
+            .map_err(to_500)?;
+        let api_keys = db.get_api_keys().await.map_err(to_500)?;
         Ok(Ok(Template::render(
             "admin",
-            make_admin_context(logged_in, ref_count, members),
+            make_admin_context(
+                logged_in,
+                referrals,
+                members,
+                history,
+                lockouts,
+                failed_kicks,
+                api_keys,
+            ),
         )))
     } else {
         Ok(Err(Status::Forbidden))
@@ -342,9 +799,7 @@ async fn admin_user_json(
     config: &State<Config>,
     db: &State<OrgDbClient>,
 ) -> Result<Result<Json<HashMap<String, serde_json::Value>>, Status>, ErrorStatus> {
-    let logged_in = make_logged_in_context(&session, &config);
-
-    if logged_in.admin {
+    if is_admin(&session, config) {
         let members = db.get_members().await.map_err(to_500)?;
         let mut map: HashMap<String, serde_json::Value> = HashMap::new();
         for member in members {
@@ -362,8 +817,9 @@ async fn admin_kick(
     who: String,
     session: Session,
     config: &State<Config>,
+    locale: Locale,
 ) -> Result<Template, Status> {
-    let logged_in = make_logged_in_context(&session, &config);
+    let logged_in = make_logged_in_context(&session, config, &locale);
 
     if logged_in.admin {
         Ok(Template::render(
@@ -383,64 +839,363 @@ async fn admin_kick_confirmed(
     db: &State<OrgDbClient>,
     http_client: &State<reqwest::Client>,
 ) -> Result<Result<Redirect, Status>, ErrorStatus> {
-    let logged_in = make_logged_in_context(&session, &config);
-
-    if logged_in.admin {
-        db.remove_membership_by_lichess_id(&who)
+    if is_admin(&session, config) {
+        let membership = db.get_member_for_lichess_id(&who).await.map_err(to_500)?;
+        db.end_membership_by_lichess_id(&who, db::ENDED_KICKED)
             .await
             .map_err(to_500)?;
         lichess::try_kick_from_team(
-            &http_client,
+            http_client,
             &config.lichess.personal_api_token,
-            "lichess.org",
+            &config.lichess.domain,
             &config.org.team_id,
             &who,
         )
         .await
         .map_err(to_500)?;
+        if let Some(membership) = &membership {
+            announce::ended(
+                db,
+                &config.webhooks,
+                &config.azolve,
+                webhooks::KICKED,
+                membership,
+            )
+            .await
+            .map_err(to_500)?;
+        }
         Ok(Ok(Redirect::to(uri!(admin))))
     } else {
         Ok(Err(Status::Forbidden))
     }
 }
 
-#[get("/org-ref")]
+#[post("/admin/lockouts/<kind>/<subject>/clear")]
+async fn admin_clear_lockout(
+    kind: String,
+    subject: String,
+    session: Session,
+    config: &State<Config>,
+    db: &State<OrgDbClient>,
+) -> Result<Result<Redirect, Status>, ErrorStatus> {
+    if !is_admin(&session, config) {
+        Ok(Err(Status::Forbidden))
+    } else if !linklimit::is_valid_kind(&kind) {
+        Ok(Err(Status::NotFound))
+    } else {
+        db.clear_link_attempts(&kind, &subject)
+            .await
+            .map_err(to_500)?;
+        Ok(Ok(Redirect::to(uri!(admin))))
+    }
+}
+
+#[post("/admin/failed-kicks/<id>/retry")]
+async fn admin_retry_failed_kick(
+    id: i32,
+    session: Session,
+    config: &State<Config>,
+    db: &State<OrgDbClient>,
+) -> Result<Result<Redirect, Status>, ErrorStatus> {
+    if is_admin(&session, config) {
+        db.retry_failed_kick(id).await.map_err(to_500)?;
+        Ok(Ok(Redirect::to(uri!(admin))))
+    } else {
+        Ok(Err(Status::Forbidden))
+    }
+}
+
+#[post("/admin/failed-kicks/<id>/dismiss")]
+async fn admin_dismiss_failed_kick(
+    id: i32,
+    session: Session,
+    config: &State<Config>,
+    db: &State<OrgDbClient>,
+) -> Result<Result<Redirect, Status>, ErrorStatus> {
+    if is_admin(&session, config) {
+        db.dismiss_failed_kick(id).await.map_err(to_500)?;
+        Ok(Ok(Redirect::to(uri!(admin))))
+    } else {
+        Ok(Err(Status::Forbidden))
+    }
+}
+
+#[derive(FromForm)]
+struct NewApiKey {
+    name: String,
+    scopes: Vec<String>,
+}
+
+#[post("/admin/api-keys", data = "<form>")]
+async fn admin_create_api_key(
+    form: Form<NewApiKey>,
+    session: Session,
+    config: &State<Config>,
+    db: &State<OrgDbClient>,
+    locale: Locale,
+) -> Result<Result<Template, Status>, ErrorStatus> {
+    if !is_admin(&session, config) {
+        return Ok(Err(Status::Forbidden));
+    }
+    let name = form.name.trim();
+    if name.is_empty() || !form.scopes.iter().all(|s| apikey::is_valid_scope(s)) {
+        return Ok(Err(Status::BadRequest));
+    }
+
+    let (key, prefix) = apikey::generate().map_err(to_500)?;
+    db.create_api_key(
+        name,
+        &prefix,
+        &apikey::hash(&key),
+        &form.scopes,
+        &session.lichess_id,
+    )
+    .await
+    .map_err(to_500)?;
+    db.audit(
+        "api_key_created",
+        Some(&session.lichess_id),
+        None,
+        &format!(
+            "name={} prefix={} scopes={}",
+            name,
+            prefix,
+            form.scopes.join(",")
+        ),
+    )
+    .await
+    .map_err(to_500)?;
+
+    let logged_in = make_logged_in_context(&session, config, &locale);
+    Ok(Ok(Template::render(
+        "apikey",
+        make_api_key_created_context(logged_in, name.to_string(), key),
+    )))
+}
+
+#[post("/admin/api-keys/<id>/revoke")]
+async fn admin_revoke_api_key(
+    id: i32,
+    session: Session,
+    config: &State<Config>,
+    db: &State<OrgDbClient>,
+) -> Result<Result<Redirect, Status>, ErrorStatus> {
+    if is_admin(&session, config) {
+        if db.revoke_api_key(id).await.map_err(to_500)? > 0 {
+            db.audit(
+                "api_key_revoked",
+                Some(&session.lichess_id),
+                None,
+                &format!("id={}", id),
+            )
+            .await
+            .map_err(to_500)?;
+        }
+        Ok(Ok(Redirect::to(uri!(admin))))
+    } else {
+        Ok(Err(Status::Forbidden))
+    }
+}
+
+async fn export_tournament(
+    kind: &str,
+    id: &str,
+    config: &Config,
+    db: &OrgDbClient,
+    http_client: &reqwest::Client,
+) -> Result<Result<(TournamentKind, tournament::TournamentExport), Status>, ErrorStatus> {
+    let Some(kind) = TournamentKind::parse(kind) else {
+        return Ok(Err(Status::BadRequest));
+    };
+    if !tournament::is_valid_id(id) {
+        return Ok(Err(Status::BadRequest));
+    }
+    let timezone = org::timezone_from_string(&config.org.timezone).map_err(to_500)?;
+    match tournament::export(db, http_client, &config.lichess, timezone, kind, id)
+        .await
+        .map_err(to_500)?
+    {
+        Some(export) => Ok(Ok((kind, export))),
+        None => Ok(Err(Status::NotFound)),
+    }
+}
+
+#[get("/admin/tournament?<kind>&<id>")]
+async fn admin_tournament(
+    kind: &str,
+    id: &str,
+    session: Session,
+    config: &State<Config>,
+    db: &State<OrgDbClient>,
+    http_client: &State<reqwest::Client>,
+    locale: Locale,
+) -> Result<Result<Template, Status>, ErrorStatus> {
+    let logged_in = make_logged_in_context(&session, config, &locale);
+    if !logged_in.admin {
+        return Ok(Err(Status::Forbidden));
+    }
+    let (kind, export) = match export_tournament(kind, id, config, db, http_client).await? {
+        Ok(exported) => exported,
+        Err(status) => return Ok(Err(status)),
+    };
+    Ok(Ok(Template::render(
+        "tournament",
+        make_tournament_context(logged_in, kind, id.to_string(), export),
+    )))
+}
+
+#[get("/admin/tournament/ratings.csv?<kind>&<id>")]
+async fn admin_tournament_ratings(
+    kind: &str,
+    id: &str,
+    session: Session,
+    config: &State<Config>,
+    db: &State<OrgDbClient>,
+    http_client: &State<reqwest::Client>,
+) -> Result<Result<Download<(ContentType, String)>, Status>, ErrorStatus> {
+    if !is_admin(&session, config) {
+        return Ok(Err(Status::Forbidden));
+    }
+    let (_, export) = match export_tournament(kind, id, config, db, http_client).await? {
+        Ok(exported) => exported,
+        Err(status) => return Ok(Err(status)),
+    };
+    Ok(Ok(Download {
+        inner: (ContentType::CSV, tournament::ratings_csv(&export)),
+        disposition: Header::new(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}-ratings.csv\"", id),
+        ),
+    }))
+}
+
+#[get("/grand-prix")]
+async fn grand_prix_index(config: &State<Config>, locale: Locale) -> Template {
+    Template::render(
+        "grandprixindex",
+        make_grand_prix_index_context(config, &locale),
+    )
+}
+
+#[get("/grand-prix/<slug>")]
+async fn grand_prix(
+    slug: &str,
+    config: &State<Config>,
+    db: &State<OrgDbClient>,
+    locale: Locale,
+) -> Result<Result<Template, Status>, ErrorStatus> {
+    let Some(series) = config
+        .grand_prix
+        .series
+        .iter()
+        .find(|series| series.slug == slug)
+    else {
+        return Ok(Err(Status::NotFound));
+    };
+    let standings = grandprix::standings(
+        db,
+        &config.grand_prix,
+        &config.org.team_id,
+        org::timezone_from_string(&config.org.timezone).map_err(to_500)?,
+        series,
+    )
+    .await
+    .map_err(to_500)?;
+    Ok(Ok(Template::render(
+        "grandprix",
+        make_grand_prix_context(config, &locale, standings),
+    )))
+}
+
+// Called by the membership system when a membership is renewed or ends, so that the link does not
+// have to wait for the member or for the expiry job.
+#[post("/webhooks/membership", data = "<body>")]
+async fn membership_webhook(
+    signature: memberhook::Signature,
+    body: String,
+    config: &State<Config>,
+    db: &State<OrgDbClient>,
+    http_client: &State<reqwest::Client>,
+) -> Result<Result<Json<memberhook::UpdateResult>, Status>, ErrorStatus> {
+    if !memberhook::is_authentic(&config.membership_webhook, &signature, &body) {
+        return Ok(Err(Status::Unauthorized));
+    }
+    let timezone = org::timezone_from_string(&config.org.timezone).map_err(to_500)?;
+    let Some(update) = serde_json::from_str::<memberhook::StatusUpdate>(&body)
+        .ok()
+        .filter(|update| memberhook::is_valid(update, timezone))
+    else {
+        return Ok(Err(Status::BadRequest));
+    };
+    let result = memberhook::apply(db, http_client, config, &update)
+        .await
+        .map_err(to_500)?;
+    Ok(Ok(Json(result)))
+}
+
+#[get("/lang/<code>")]
+async fn set_language(
+    code: String,
+    cookies: &CookieJar<'_>,
+    catalogue: &State<Arc<Catalogue>>,
+) -> Redirect {
+    i18n::set_locale_cookie(cookies, catalogue, &code);
+    Redirect::to(uri!(index))
+}
+
+const MAX_SOURCE_LENGTH: usize = 64;
+
+// Campaign sources come from links shared anywhere, so anything unexpected is recorded as no source.
+fn campaign_source(source: Option<String>) -> String {
+    source
+        .filter(|s| {
+            s.len() <= MAX_SOURCE_LENGTH
+                && s.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        })
+        .unwrap_or_default()
+}
+
+#[get("/org-ref?<source>")]
 async fn referral(
+    source: Option<String>,
     session: Session,
     config: &State<Config>,
     db: &State<OrgDbClient>,
 ) -> Result<Redirect, ErrorStatus> {
-    db.referral_click(&session.lichess_id)
+    db.record_referral_click(&session.lichess_id, &campaign_source(source))
         .await
         .map_err(to_500)?;
     Ok(Redirect::to(config.org.referral_link.clone()))
 }
 
-#[launch]
-async fn rocket() -> _ {
-    let config_contents = fs::read_to_string("Config.toml").expect("Cannot read Config.toml");
-    let config: Config = toml::from_str(&config_contents).expect("Invalid Config.toml");
-
-    let db_client = db::connect(&config.server.postgres_options).await.unwrap();
-
-    if config.expiry.enable {
-        expwatch::launch(
-            db_client.clone(),
-            String::from("lichess.org"),
-            config.org.team_id.clone(),
-            config.lichess.personal_api_token.clone(),
-            config.server.expiry_check_interval_seconds,
-            org::timezone_from_string(&config.org.timezone).unwrap(),
-            config.expiry.renewal_month,
-            config.expiry.renewal_day,
-        );
-    }
+#[get("/org-ref?<source>", rank = 2)]
+async fn referral_anonymous(
+    source: Option<String>,
+    cookies: &CookieJar<'_>,
+    config: &State<Config>,
+    db: &State<OrgDbClient>,
+) -> Result<Redirect, ErrorStatus> {
+    let visitor = session::referral_visitor(cookies).map_err(to_500)?;
+    db.record_anonymous_referral_click(&visitor, &campaign_source(source))
+        .await
+        .map_err(to_500)?;
+    Ok(Redirect::to(config.org.referral_link.clone()))
+}
 
+fn rocket(config: Config, db_client: OrgDbClient, catalogue: Catalogue) -> Rocket<Build> {
     let http_client = reqwest::Client::new();
+    let catalogue = Arc::new(catalogue);
+    let template_catalogue = catalogue.clone();
 
     rocket::build()
-        .attach(Template::fairing())
+        .attach(Template::custom(move |engines| {
+            engines
+                .tera
+                .register_function("t", i18n::TemplateFunction(template_catalogue.clone()));
+        }))
         .manage(config)
+        .manage(catalogue)
         .manage(http_client)
         .manage(db_client)
         .mount(
@@ -454,13 +1209,140 @@ async fn rocket() -> _ {
                 form_redirect_index,
                 link_memberships,
                 logout,
+                show_unlink,
+                unlink_redirect_index,
+                unlink,
+                unlink_unauthenticated,
+                export_personal_data,
+                export_redirect_index,
                 try_link_unauthenticated,
+                transfer_membership,
+                transfer_unauthenticated,
                 admin,
                 admin_unauthed,
                 admin_user_json,
                 admin_kick,
                 admin_kick_confirmed,
-                referral
+                admin_clear_lockout,
+                admin_retry_failed_kick,
+                admin_dismiss_failed_kick,
+                admin_create_api_key,
+                admin_revoke_api_key,
+                admin_tournament,
+                admin_tournament_ratings,
+                grand_prix_index,
+                grand_prix,
+                membership_webhook,
+                set_language,
+                referral,
+                referral_anonymous
             ],
         )
+        .mount(api::BASE, api::routes())
+        .register(api::BASE, api::catchers())
+}
+
+async fn serve(config: Config, db_client: OrgDbClient) -> Result<(), ErrorBox> {
+    let catalogue = Catalogue::load(i18n::LOCALES_DIRECTORY, &config.org, &config.lichess.domain)?;
+
+    let leadership = leader::launch(db_client.clone(), config.leader_election.lease_seconds)?;
+    let timezone = org::timezone_from_string(&config.org.timezone)?;
+
+    if config.expiry.enable {
+        expwatch::launch(
+            db_client.clone(),
+            expwatch::KickSettings::from_config(&config),
+            leadership.clone(),
+            schedule::parse(&config.expiry.schedule)?,
+            timezone,
+            config.expiry.renewal_month,
+            config.expiry.renewal_day,
+        );
+    }
+
+    if config.reverification.enable {
+        reverify::launch(
+            db_client.clone(),
+            reverify::ReverifySettings::from_config(&config)?,
+            leadership.clone(),
+            schedule::parse(&config.reverification.schedule)?,
+        );
+    }
+
+    if !config.grand_prix.series.is_empty() {
+        grandprix::launch(
+            db_client.clone(),
+            grandprix::GrandPrixSettings::from_config(&config)?,
+            leadership.clone(),
+            schedule::parse(&config.grand_prix.schedule)?,
+        );
+    }
+
+    if verifier::can_write_back(&config.azolve) {
+        writeback::launch(
+            db_client.clone(),
+            writeback::WritebackSettings {
+                azolve: config.azolve.clone(),
+                queue: config.writeback.clone(),
+            },
+            leadership.clone(),
+        );
+    }
+
+    if !config.webhooks.urls.is_empty() {
+        webhooks::launch(
+            db_client.clone(),
+            config.webhooks.clone(),
+            leadership.clone(),
+        );
+    }
+
+    retention::launch(
+        db_client.clone(),
+        config.retention.clone(),
+        leadership,
+        schedule::parse(&config.retention.schedule)?,
+        timezone,
+    );
+
+    rocket(config, db_client, catalogue)
+        .launch()
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[rocket::main]
+async fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|a| a == "--help" || a == "-h") {
+        println!("{}", cli::USAGE);
+        return;
+    }
+
+    let config_path = match cli::take_config_path(&mut args) {
+        Ok(path) => path
+            .or_else(|| std::env::var(config::PATH_VARIABLE).ok())
+            .unwrap_or_else(|| String::from(config::DEFAULT_PATH)),
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let result = match cli::parse(&args) {
+        Ok(command) => match config::load(&config_path) {
+            Ok(config) => cli::run(command, config).await,
+            Err(e) => Err(e),
+        },
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 }
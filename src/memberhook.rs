@@ -0,0 +1,213 @@
+use crate::announce;
+use crate::config::{Config, MembershipWebhookConfig};
+use crate::db::{self, Membership, OrgDbClient};
+use crate::lichess::{self, KickOutcome};
+use crate::org;
+use crate::types::*;
+use crate::webhooks;
+use chrono::Utc;
+use chrono_tz::Tz;
+use rocket::Request;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+
+pub const ACTIVE: &str = "active";
+pub const LAPSED: &str = "lapsed";
+pub const CANCELLED: &str = "cancelled";
+pub const SUSPENDED: &str = "suspended";
+
+// The timestamp and signature headers of a request from the membership system, signed the same
+// way as our outgoing webhooks.
+pub struct Signature {
+    timestamp: String,
+    signature: String,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Signature {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Signature, Self::Error> {
+        // Without a secret the endpoint does not exist, whatever the request carries.
+        if request
+            .rocket()
+            .state::<Config>()
+            .is_none_or(|config| config.membership_webhook.secret.is_empty())
+        {
+            return Outcome::Forward(Status::NotFound);
+        }
+        let headers = request.headers();
+        match (
+            headers.get_one(webhooks::TIMESTAMP_HEADER),
+            headers.get_one(webhooks::SIGNATURE_HEADER),
+        ) {
+            (Some(timestamp), Some(signature)) => Outcome::Success(Signature {
+                timestamp: timestamp.to_string(),
+                signature: signature.to_string(),
+            }),
+            _ => Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+// Checks the signature of `body`, and rejects requests older than `max_age_seconds` so that a
+// captured request cannot be replayed later.
+pub fn is_authentic(config: &MembershipWebhookConfig, signature: &Signature, body: &str) -> bool {
+    let Ok(timestamp) = signature.timestamp.parse::<i64>() else {
+        return false;
+    };
+    if (Utc::now().timestamp() - timestamp).abs() > config.max_age_seconds {
+        return false;
+    }
+    let Ok(expected) = webhooks::sign(&config.secret, &signature.timestamp, body) else {
+        return false;
+    };
+    bool::from(
+        format!("sha256={}", expected)
+            .as_bytes()
+            .ct_eq(signature.signature.as_bytes()),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct StatusUpdate {
+    pub member_id: String,
+    pub status: String,
+    pub exp_year: Option<i32>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateResult {
+    // The member ID is not linked to a Lichess account, so there is nothing to do.
+    NotLinked,
+    Updated,
+    // The membership already expires in the given year or later, so it was left as it is.
+    Unchanged,
+    Kicked,
+    // Kicking failed, so it was left to the kick queue.
+    Queued,
+    // Kicking failed, and the kick queue already has this membership pending, failed or dismissed.
+    NotQueued,
+}
+
+impl UpdateResult {
+    fn as_str(&self) -> &'static str {
+        match self {
+            UpdateResult::NotLinked => "not_linked",
+            UpdateResult::Updated => "updated",
+            UpdateResult::Unchanged => "unchanged",
+            UpdateResult::Kicked => "kicked",
+            UpdateResult::Queued => "queued",
+            UpdateResult::NotQueued => "not_queued",
+        }
+    }
+}
+
+pub fn is_valid(update: &StatusUpdate, timezone: Tz) -> bool {
+    match update.status.as_str() {
+        ACTIVE => update
+            .exp_year
+            .is_some_and(|exp_year| org::is_plausible_exp_year(exp_year, timezone)),
+        LAPSED | CANCELLED | SUSPENDED => true,
+        _ => false,
+    }
+}
+
+async fn renew(
+    db: &OrgDbClient,
+    config: &Config,
+    member: Membership,
+    exp_year: i32,
+) -> Result<UpdateResult, ErrorBox> {
+    if db.extend_expiry_year(&member.org_id, exp_year).await? == 0 {
+        return Ok(UpdateResult::Unchanged);
+    }
+    webhooks::notify(
+        db,
+        &config.webhooks,
+        webhooks::RENEWED,
+        &Membership { exp_year, ..member },
+        None,
+    )
+    .await?;
+    Ok(UpdateResult::Updated)
+}
+
+// Kicks the member from the team and ends the link, or leaves the kick to the kick queue when
+// Lichess cannot be reached.
+pub async fn kick_or_queue(
+    db: &OrgDbClient,
+    http_client: &reqwest::Client,
+    config: &Config,
+    member: Membership,
+) -> Result<UpdateResult, ErrorBox> {
+    match lichess::kick_from_team_outcome(
+        http_client,
+        &config.lichess.personal_api_token,
+        &config.lichess.domain,
+        &config.org.team_id,
+        &member.lichess_id,
+    )
+    .await
+    {
+        KickOutcome::Kicked => {
+            db.end_membership(&member.org_id, db::ENDED_KICKED).await?;
+            announce::ended(
+                db,
+                &config.webhooks,
+                &config.azolve,
+                webhooks::KICKED,
+                &member,
+            )
+            .await?;
+            Ok(UpdateResult::Kicked)
+        }
+        _ => {
+            let queued = db
+                .enqueue_kick(&member.lichess_id, &member.org_id, member.exp_year)
+                .await?;
+            Ok(if queued > 0 {
+                UpdateResult::Queued
+            } else {
+                UpdateResult::NotQueued
+            })
+        }
+    }
+}
+
+// Applies a validated status update to the linked membership, if there is one.
+pub async fn apply(
+    db: &OrgDbClient,
+    http_client: &reqwest::Client,
+    config: &Config,
+    update: &StatusUpdate,
+) -> Result<UpdateResult, ErrorBox> {
+    let Some(member) = db.get_member_for_org_id(&update.member_id).await? else {
+        return Ok(UpdateResult::NotLinked);
+    };
+    let lichess_id = member.lichess_id.clone();
+
+    let result = match update.exp_year {
+        Some(exp_year) if update.status == ACTIVE => renew(db, config, member, exp_year).await?,
+        _ => kick_or_queue(db, http_client, config, member).await?,
+    };
+
+    db.audit(
+        "membership_webhook",
+        Some(&lichess_id),
+        Some(&update.member_id),
+        &format!(
+            "status={} exp_year={} result={}",
+            &update.status,
+            update
+                .exp_year
+                .map_or(String::new(), |year| year.to_string()),
+            result.as_str()
+        ),
+    )
+    .await?;
+    Ok(result)
+}
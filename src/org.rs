@@ -1,17 +1,26 @@
 use crate::types::*;
 use chrono::{Datelike, TimeZone, Utc};
 use chrono_tz::Tz;
+use serde::Serialize;
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MembershipState {
+    Active,
+    ExpiredInGrace,
+}
 
 pub fn timezone_from_string(timezone: &str) -> Result<Tz, ErrorBox> {
     Ok(timezone.parse()?)
 }
 
-pub fn is_past_expiry(year: i32, timezone: Tz, month: u32, day: u32) -> bool {
+// None when the date does not exist, e.g. because the year is out of range.
+pub fn is_past_expiry(year: i32, timezone: Tz, month: u32, day: u32) -> Option<bool> {
     let expiry = timezone
         .with_ymd_and_hms(year, month, day, 23, 59, 59)
-        .unwrap();
+        .earliest()?;
     let now = timezone.from_utc_datetime(&Utc::now().naive_utc());
-    now > expiry
+    Some(now > expiry)
 }
 
 pub fn current_year(timezone: Tz) -> i32 {
@@ -19,13 +28,40 @@ pub fn current_year(timezone: Tz) -> i32 {
 }
 
 pub fn is_past_expiry_this_year(timezone: Tz, month: u32, day: u32) -> bool {
-    is_past_expiry(current_year(timezone), timezone, month, day)
+    is_past_expiry(current_year(timezone), timezone, month, day).unwrap_or(false)
+}
+
+// Expiry years given by administrators or the membership system must lie in this range around the
+// current year.
+pub fn is_plausible_exp_year(exp_year: i32, timezone: Tz) -> bool {
+    let year = current_year(timezone);
+    (year - 1..=year + 10).contains(&exp_year)
 }
 
-pub fn is_past_renewal(exp_year: i32, timezone: Tz, month: u32, day: u32) -> bool {
+pub fn new_membership_exp_year(timezone: Tz, month: u32, day: u32) -> i32 {
+    current_year(timezone)
+        + if is_past_expiry_this_year(timezone, month, day) {
+            1
+        } else {
+            0
+        }
+}
+
+// None when the date does not exist, e.g. because the year is out of range.
+pub fn is_past_renewal(exp_year: i32, timezone: Tz, month: u32, day: u32) -> Option<bool> {
     let renewal_deadline = timezone
         .with_ymd_and_hms(exp_year, month, day, 23, 59, 59)
-        .unwrap();
+        .earliest()?;
     let now = timezone.from_utc_datetime(&Utc::now().naive_utc());
-    now > renewal_deadline
+    Some(now > renewal_deadline)
+}
+
+// Linked memberships stay in the grace period after expiry until they are renewed or kicked.
+// Kicked and unlinked memberships are recorded in membership_history instead.
+pub fn membership_state(exp_year: i32, timezone: Tz, month: u32, day: u32) -> MembershipState {
+    if is_past_expiry(exp_year, timezone, month, day).unwrap_or(false) {
+        MembershipState::ExpiredInGrace
+    } else {
+        MembershipState::Active
+    }
 }
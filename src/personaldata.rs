@@ -0,0 +1,59 @@
+use crate::db::{
+    AuditEvent, LinkLockout, Membership, OrgDbClient, PastMembership, QueuedKick, ReferralClick,
+    TournamentResultRecord, WebhookDelivery, WritebackRecord,
+};
+use crate::linklimit;
+use crate::types::*;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+// Everything stored about one Lichess account, for subject access requests.
+#[derive(Serialize)]
+pub struct PersonalData {
+    pub exported: DateTime<Utc>,
+    pub lichess_id: String,
+    pub membership: Option<Membership>,
+    pub membership_history: Vec<PastMembership>,
+    pub referral_clicked: bool,
+    pub referral_clicks: Vec<ReferralClick>,
+    pub link_attempts: Vec<LinkLockout>,
+    pub kicks: Vec<QueuedKick>,
+    pub webhooks: Vec<WebhookDelivery>,
+    pub writebacks: Vec<WritebackRecord>,
+    pub tournament_results: Vec<TournamentResultRecord>,
+    pub audit: Vec<AuditEvent>,
+}
+
+pub async fn collect(db: &OrgDbClient, lichess_id: &str) -> Result<PersonalData, ErrorBox> {
+    let membership = db.get_member_for_lichess_id(lichess_id).await?;
+
+    let mut link_attempts = vec![];
+    if let Some(attempts) = db
+        .get_link_attempts(linklimit::LICHESS_ACCOUNT, lichess_id)
+        .await?
+    {
+        link_attempts.push(attempts);
+    }
+    if let Some(member) = &membership
+        && let Some(attempts) = db
+            .get_link_attempts(linklimit::ORG_ID, &member.org_id)
+            .await?
+    {
+        link_attempts.push(attempts);
+    }
+
+    Ok(PersonalData {
+        exported: Utc::now(),
+        lichess_id: lichess_id.to_string(),
+        membership_history: db.get_membership_history_for_lichess_id(lichess_id).await?,
+        referral_clicked: db.has_referral_click(lichess_id).await?,
+        referral_clicks: db.get_referral_clicks_for_lichess_id(lichess_id).await?,
+        link_attempts,
+        kicks: db.get_kicks_for_lichess_id(lichess_id).await?,
+        webhooks: db.get_webhooks_for_lichess_id(lichess_id).await?,
+        writebacks: db.get_writebacks_for_lichess_id(lichess_id).await?,
+        tournament_results: db.get_tournament_results_for_lichess_id(lichess_id).await?,
+        audit: db.get_audit_events_for_lichess_id(lichess_id).await?,
+        membership,
+    })
+}
@@ -0,0 +1,201 @@
+use crate::config::RetentionConfig;
+use crate::db::OrgDbClient;
+use crate::leader::Leadership;
+use crate::schedule;
+use crate::textlog;
+use crate::types::*;
+use chrono_tz::Tz;
+use cron::Schedule;
+
+pub const DELETE: &str = "delete";
+pub const ANONYMISE: &str = "anonymise";
+
+const JOB_NAME: &str = "retention";
+
+pub fn is_valid_action(action: &str) -> bool {
+    action == DELETE || action == ANONYMISE
+}
+
+pub struct Target {
+    pub table: &'static str,
+    pub time_column: &'static str,
+    pub id_columns: &'static [&'static str],
+    // Rows that must be kept regardless of their age.
+    pub keep: &'static str,
+}
+
+const REFERRALS: &[Target] = &[
+    Target {
+        table: "ref",
+        time_column: "clicked",
+        id_columns: &["lichessid"],
+        keep: "false",
+    },
+    Target {
+        table: "referral_clicks",
+        time_column: "clicked",
+        id_columns: &["lichessid", "visitor"],
+        keep: "false",
+    },
+];
+
+const AUDIT: &[Target] = &[Target {
+    table: "audit",
+    time_column: "at",
+    id_columns: &["lichessid", "orgid"],
+    keep: "false",
+}];
+
+const MEMBERSHIP_HISTORY: &[Target] = &[Target {
+    table: "membership_history",
+    time_column: "ended",
+    id_columns: &["lichessid", "orgid"],
+    keep: "false",
+}];
+
+// Pending and failed kicks still need to happen or be looked at.
+const KICK_QUEUE: &[Target] = &[Target {
+    table: "kick_queue",
+    time_column: "created",
+    id_columns: &["lichessid", "orgid"],
+    keep: "state IN ('pending', 'failed')",
+}];
+
+// The payload repeats the Lichess ID, so it is anonymised as a whole.
+const WEBHOOK_QUEUE: &[Target] = &[Target {
+    table: "webhook_queue",
+    time_column: "created",
+    id_columns: &["lichessid", "payload"],
+    keep: "state IN ('pending', 'failed')",
+}];
+
+const WRITEBACK_QUEUE: &[Target] = &[Target {
+    table: "writeback_queue",
+    time_column: "created",
+    id_columns: &["lichessid", "orgid"],
+    keep: "state IN ('pending', 'failed')",
+}];
+
+// Aged by the start of the tournament. Purged results no longer count towards the Grand Prix, and
+// the tournament stays cached so they are not fetched again.
+const TOURNAMENT_RESULTS: &[Target] = &[Target {
+    table: "tournament_results",
+    time_column: "(SELECT starts FROM tournaments WHERE tournaments.id = tournament_results.tournamentid)",
+    id_columns: &["lichessid"],
+    keep: "false",
+}];
+
+// Lockouts still in force are kept, or purging would lift them early.
+const LINK_ATTEMPTS: &[Target] = &[Target {
+    table: "link_attempts",
+    time_column: "last_failure",
+    id_columns: &["subject"],
+    keep: "locked_until IS NOT NULL AND locked_until > now()",
+}];
+
+pub struct Policy<'a> {
+    pub name: &'static str,
+    pub targets: &'static [Target],
+    pub days: i64,
+    pub action: &'a str,
+}
+
+pub fn policies(config: &RetentionConfig) -> Vec<Policy<'_>> {
+    vec![
+        Policy {
+            name: "referral",
+            targets: REFERRALS,
+            days: config.referral_days,
+            action: &config.referral_action,
+        },
+        Policy {
+            name: "audit",
+            targets: AUDIT,
+            days: config.audit_days,
+            action: &config.audit_action,
+        },
+        Policy {
+            name: "membership_history",
+            targets: MEMBERSHIP_HISTORY,
+            days: config.membership_history_days,
+            action: &config.membership_history_action,
+        },
+        Policy {
+            name: "kick_queue",
+            targets: KICK_QUEUE,
+            days: config.kick_queue_days,
+            action: &config.kick_queue_action,
+        },
+        Policy {
+            name: "webhook_queue",
+            targets: WEBHOOK_QUEUE,
+            days: config.webhook_queue_days,
+            action: &config.webhook_queue_action,
+        },
+        Policy {
+            name: "writeback_queue",
+            targets: WRITEBACK_QUEUE,
+            days: config.writeback_queue_days,
+            action: &config.writeback_queue_action,
+        },
+        Policy {
+            name: "tournament_results",
+            targets: TOURNAMENT_RESULTS,
+            days: config.tournament_results_days,
+            action: &config.tournament_results_action,
+        },
+        Policy {
+            name: "link_attempts",
+            targets: LINK_ATTEMPTS,
+            days: config.link_attempts_days,
+            action: &config.link_attempts_action,
+        },
+    ]
+}
+
+pub async fn purge(db: &OrgDbClient, config: &RetentionConfig) -> Result<(), ErrorBox> {
+    for policy in policies(config) {
+        if policy.days == 0 {
+            continue;
+        }
+        for target in policy.targets {
+            let (count, done) = if policy.action == ANONYMISE {
+                (
+                    db.anonymise_older_than(target, policy.days, &config.salt)
+                        .await?,
+                    "Anonymised",
+                )
+            } else {
+                (db.delete_older_than(target, policy.days).await?, "Deleted")
+            };
+            let message = format!(
+                "{} {} {} record(s) older than {} days",
+                done, count, target.table, policy.days
+            );
+            textlog::log_to("retention.log", &message);
+        }
+    }
+    Ok(())
+}
+
+pub fn launch(
+    db_client: OrgDbClient,
+    config: RetentionConfig,
+    leadership: Leadership,
+    retention_schedule: Schedule,
+    timezone: Tz,
+) {
+    let purge_db_client = db_client.clone();
+    rocket::tokio::task::spawn(schedule::run_scheduled(
+        db_client,
+        JOB_NAME,
+        retention_schedule,
+        timezone,
+        leadership,
+        move || {
+            let db_client = purge_db_client.clone();
+            let config = config.clone();
+            async move { purge(&db_client, &config).await }
+        },
+    ));
+}
@@ -0,0 +1,10 @@
+// Queued kicks, webhooks and write-backs are retried at least daily, however often they failed.
+pub const MAX_RETRY_SECONDS: i64 = 86400;
+
+// Seconds to wait after the `attempts`th failure: `base_seconds` after the first one, doubling
+// with every further failure, and never more than `max_seconds`.
+pub fn backoff_delay(attempts: i32, base_seconds: i64, max_seconds: i64) -> i64 {
+    base_seconds
+        .saturating_mul(1i64 << (attempts - 1).clamp(0, 62))
+        .min(max_seconds)
+}
@@ -0,0 +1,186 @@
+use crate::config::{AzolveConfig, Config, TestingConfig, WebhookConfig};
+use crate::db::{Membership, OrgDbClient};
+use crate::leader::Leadership;
+use crate::org;
+use crate::schedule;
+use crate::textlog;
+use crate::types::*;
+use crate::verifier::{self, Lookup};
+use crate::webhooks;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use chrono_tz::Tz;
+use cron::Schedule;
+use rocket::tokio::time::sleep;
+
+const JOB_NAME: &str = "reverification";
+const LOG_FILE: &str = "reverification.log";
+
+#[derive(Clone)]
+pub struct ReverifySettings {
+    pub azolve: AzolveConfig,
+    pub testing: TestingConfig,
+    pub webhooks: WebhookConfig,
+    pub delay_ms: u64,
+    pub timezone: Tz,
+    pub membership_month: u32,
+    pub membership_day: u32,
+    pub renewal_month: u32,
+    pub renewal_day: u32,
+    pub window_days: i64,
+}
+
+impl ReverifySettings {
+    pub fn from_config(config: &Config) -> Result<ReverifySettings, ErrorBox> {
+        Ok(ReverifySettings {
+            azolve: config.azolve.clone(),
+            testing: config.testing.clone(),
+            webhooks: config.webhooks.clone(),
+            delay_ms: config.reverification.delay_ms,
+            timezone: org::timezone_from_string(&config.org.timezone)?,
+            membership_month: config.expiry.membership_month,
+            membership_day: config.expiry.membership_day,
+            renewal_month: config.expiry.renewal_month,
+            renewal_day: config.expiry.renewal_day,
+            window_days: config.reverification.window_days,
+        })
+    }
+}
+
+// Returns whether the membership was extended.
+async fn reverify_member(
+    db: &OrgDbClient,
+    http_client: &reqwest::Client,
+    settings: &ReverifySettings,
+    member: Membership,
+) -> Result<bool, ErrorBox> {
+    let test_exp_year = org::new_membership_exp_year(
+        settings.timezone,
+        settings.membership_month,
+        settings.membership_day,
+    );
+    let exp_year = match verifier::look_up_member(
+        db,
+        http_client,
+        &settings.azolve,
+        &settings.testing,
+        &member.lichess_id,
+        &member.org_id,
+        test_exp_year,
+    )
+    .await?
+    {
+        Lookup::ExpiryYear(exp_year)
+            if !org::is_plausible_exp_year(exp_year, settings.timezone) =>
+        {
+            textlog::log_to(
+                LOG_FILE,
+                &format!(
+                    "Not extending {}: the membership system gave the implausible expiry year {}",
+                    &member.org_id, exp_year
+                ),
+            );
+            return Ok(false);
+        }
+        Lookup::ExpiryYear(exp_year) if exp_year > member.exp_year => exp_year,
+        _ => return Ok(false),
+    };
+
+    if db.extend_expiry_year(&member.org_id, exp_year).await? == 0 {
+        return Ok(false);
+    }
+    db.audit(
+        "reverified",
+        Some(&member.lichess_id),
+        Some(&member.org_id),
+        &format!("exp_year={} previous={}", exp_year, member.exp_year),
+    )
+    .await?;
+    webhooks::notify(
+        db,
+        &settings.webhooks,
+        webhooks::RENEWED,
+        &Membership { exp_year, ..member },
+        None,
+    )
+    .await?;
+    Ok(true)
+}
+
+// Members are looked up from `window_days` before their renewal deadline, and once their membership
+// has expired until they are kicked.
+fn is_due(settings: &ReverifySettings, exp_year: i32, now: DateTime<Tz>) -> bool {
+    let expired = org::is_past_expiry(
+        exp_year,
+        settings.timezone,
+        settings.membership_month,
+        settings.membership_day,
+    );
+    let renewal_deadline = settings
+        .timezone
+        .with_ymd_and_hms(
+            exp_year,
+            settings.renewal_month,
+            settings.renewal_day,
+            23,
+            59,
+            59,
+        )
+        .earliest();
+    expired.unwrap_or(false)
+        || renewal_deadline
+            .is_some_and(|deadline| deadline - now <= Duration::days(settings.window_days))
+}
+
+// Looks up every member who is due (see `is_due`), and extends those who have renewed. Members that
+// cannot be looked up are left for the next run.
+pub async fn reverify(
+    db: &OrgDbClient,
+    http_client: &reqwest::Client,
+    settings: &ReverifySettings,
+) -> Result<(), ErrorBox> {
+    let now = Utc::now().with_timezone(&settings.timezone);
+    let members: Vec<Membership> = db
+        .get_members_with_at_most_expiry_year(org::current_year(settings.timezone))
+        .await?
+        .into_iter()
+        .filter(|member| is_due(settings, member.exp_year, now))
+        .collect();
+    let checked = members.len();
+    let mut extended = 0;
+    for member in members {
+        let org_id = member.org_id.clone();
+        match reverify_member(db, http_client, settings, member).await {
+            Ok(true) => extended += 1,
+            Ok(false) => {}
+            Err(e) => textlog::log_to(LOG_FILE, &format!("Could not look up {}: {}", org_id, e)),
+        }
+        sleep(std::time::Duration::from_millis(settings.delay_ms)).await;
+    }
+    textlog::log_to(
+        LOG_FILE,
+        &format!("Checked {} membership(s), extended {}", checked, extended),
+    );
+    Ok(())
+}
+
+pub fn launch(
+    db_client: OrgDbClient,
+    settings: ReverifySettings,
+    leadership: Leadership,
+    reverify_schedule: Schedule,
+) {
+    let reverify_db_client = db_client.clone();
+    let timezone = settings.timezone;
+    rocket::tokio::task::spawn(schedule::run_scheduled(
+        db_client,
+        JOB_NAME,
+        reverify_schedule,
+        timezone,
+        leadership,
+        move || {
+            let db_client = reverify_db_client.clone();
+            let settings = settings.clone();
+            async move { reverify(&db_client, &reqwest::Client::new(), &settings).await }
+        },
+    ));
+}
@@ -0,0 +1,251 @@
+use crate::db::OrgDbClient;
+use crate::leader::Leadership;
+use crate::retry;
+use crate::textlog;
+use crate::types::*;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use cron::Schedule;
+use rocket::tokio::time::sleep;
+use std::future::Future;
+use std::str::FromStr;
+
+// How long to wait before retrying when the database cannot be reached, and before the first retry
+// of a failed run.
+const RETRY_SECONDS: u64 = 60;
+
+pub fn parse(expression: &str) -> Result<Schedule, ErrorBox> {
+    Schedule::from_str(expression).map_err(|e| format!("invalid cron expression: {}", e).into())
+}
+
+// The first scheduled time after `after`, evaluated in the organisation's timezone.
+pub fn next_run(schedule: &Schedule, timezone: Tz, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    schedule
+        .after(&after.with_timezone(&timezone))
+        .next()
+        .map(|at| at.with_timezone(&Utc))
+}
+
+// The last scheduled time before `before`, evaluated in the organisation's timezone.
+pub fn previous_run(
+    schedule: &Schedule,
+    timezone: Tz,
+    before: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    schedule
+        .after(&before.with_timezone(&timezone))
+        .next_back()
+        .map(|at| at.with_timezone(&Utc))
+}
+
+// The last scheduled time before `now` if the job has not run for it, so that a run missed while no
+// instance was up is made up for. Jobs that never ran start at their next scheduled time instead.
+fn missed_run(
+    schedule: &Schedule,
+    timezone: Tz,
+    last_run: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    let last_run = last_run?;
+    previous_run(schedule, timezone, now).filter(|previous| *previous > last_run)
+}
+
+// Runs the job once for the scheduled time, unless another instance holds the lock or
+// already ran it.
+async fn run_once<F, Fut>(
+    db: &OrgDbClient,
+    name: &str,
+    scheduled: DateTime<Utc>,
+    job: &F,
+) -> Result<(), ErrorBox>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<(), ErrorBox>>,
+{
+    let Some(lock) = db.try_lock_job(name).await? else {
+        println!("Skipping {} job: another instance is running it", name);
+        return Ok(());
+    };
+
+    let result = match db.get_job_last_run(name).await {
+        Ok(Some(last_run)) if last_run >= scheduled => Ok(()),
+        Ok(_) => match job().await {
+            Ok(()) => db.record_job_run(name, scheduled).await,
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    };
+
+    lock.release().await?;
+    result
+}
+
+// Runs the job for the scheduled time, retrying with a growing delay until it succeeds, the next
+// scheduled time comes, or this instance stops being the leader.
+async fn run_with_retries<F, Fut>(
+    db: &OrgDbClient,
+    name: &str,
+    scheduled: DateTime<Utc>,
+    next: Option<DateTime<Utc>>,
+    leadership: &Leadership,
+    job: &F,
+) where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<(), ErrorBox>>,
+{
+    let mut attempts = 0;
+    loop {
+        let Err(e) = run_once(db, name, scheduled, job).await else {
+            return;
+        };
+        attempts += 1;
+        let delay = retry::backoff_delay(attempts, RETRY_SECONDS as i64, retry::MAX_RETRY_SECONDS);
+        let retry_at = Utc::now() + chrono::Duration::seconds(delay);
+        if next.is_some_and(|next| retry_at >= next) {
+            textlog::log_to(
+                &format!("{}.error.log", name),
+                &format!(
+                    "Could not run {} job for {}, giving up before the next run: {}",
+                    name, scheduled, e
+                ),
+            );
+            return;
+        }
+        textlog::log_to(
+            &format!("{}.error.log", name),
+            &format!(
+                "Could not run {} job for {}, retrying at {}: {}",
+                name, scheduled, retry_at, e
+            ),
+        );
+        sleep(std::time::Duration::from_secs(delay as u64)).await;
+        if !leadership.is_leader() {
+            println!("Not retrying {} job: this instance is not the leader", name);
+            return;
+        }
+    }
+}
+
+// Runs `job` at every time matched by `schedule`, on the leader only. The last run is stored in the
+// database, so a restart does not run the job again before its next scheduled time, and a
+// scheduled time that passed while no instance was running is caught up on straight away.
+pub async fn run_scheduled<F, Fut>(
+    db: OrgDbClient,
+    name: &str,
+    schedule: Schedule,
+    timezone: Tz,
+    leadership: Leadership,
+    job: F,
+) where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<(), ErrorBox>>,
+{
+    // The last scheduled time this instance ran or skipped, so that it is not caught up on again when
+    // it failed for good or another instance is the leader.
+    let mut handled: Option<DateTime<Utc>> = None;
+    loop {
+        let last_run = match db.get_job_last_run(name).await {
+            Ok(last_run) => last_run,
+            Err(e) => {
+                textlog::log_to(
+                    &format!("{}.error.log", name),
+                    &format!("Could not read last {} run: {}", name, e),
+                );
+                sleep(std::time::Duration::from_secs(RETRY_SECONDS)).await;
+                continue;
+            }
+        };
+
+        let now = Utc::now();
+        let since = last_run.max(handled);
+        let missed = missed_run(&schedule, timezone, since, now);
+        let scheduled = match missed {
+            Some(missed) => missed,
+            None => {
+                let after = since.map_or(now, |since| since.max(now));
+                let Some(scheduled) = next_run(&schedule, timezone, after) else {
+                    textlog::log_to(
+                        &format!("{}.error.log", name),
+                        &format!("The {} schedule has no upcoming runs", name),
+                    );
+                    return;
+                };
+                println!("Next {} run at {}", name, scheduled);
+                if let Ok(wait) = (scheduled - Utc::now()).to_std() {
+                    sleep(wait).await;
+                }
+                scheduled
+            }
+        };
+        if !leadership.is_leader() {
+            if missed.is_some() {
+                // Leadership may not be settled yet after a restart; the leader catches up and
+                // records the run, or this instance does once it becomes the leader.
+                sleep(std::time::Duration::from_secs(RETRY_SECONDS)).await;
+            } else {
+                println!("Skipping {} job: this instance is not the leader", name);
+                handled = Some(scheduled);
+            }
+            continue;
+        }
+        if missed.is_some() {
+            println!("Catching up on the {} run due at {}", name, scheduled);
+        }
+        handled = Some(scheduled);
+
+        let next = next_run(&schedule, timezone, scheduled);
+        run_with_retries(&db, name, scheduled, next, &leadership, &job).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 3, 10, hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn previous_run_is_the_last_time_before() {
+        let daily = parse("0 0 3 * * *").unwrap();
+        assert_eq!(
+            previous_run(&daily, chrono_tz::UTC, at(12, 0)),
+            Some(at(3, 0))
+        );
+        assert_eq!(
+            next_run(&daily, chrono_tz::UTC, at(12, 0)),
+            Some(at(3, 0) + chrono::Duration::days(1))
+        );
+    }
+
+    #[test]
+    fn a_run_missed_since_the_last_one_is_caught_up() {
+        let daily = parse("0 0 3 * * *").unwrap();
+        let yesterday = at(3, 0) - chrono::Duration::days(1);
+        assert_eq!(
+            missed_run(&daily, chrono_tz::UTC, Some(yesterday), at(12, 0)),
+            Some(at(3, 0))
+        );
+    }
+
+    #[test]
+    fn nothing_is_caught_up_after_the_latest_run_or_before_the_first() {
+        let daily = parse("0 0 3 * * *").unwrap();
+        assert_eq!(
+            missed_run(&daily, chrono_tz::UTC, Some(at(3, 0)), at(12, 0)),
+            None
+        );
+        assert_eq!(missed_run(&daily, chrono_tz::UTC, None, at(12, 0)), None);
+    }
+
+    #[test]
+    fn only_the_latest_of_several_missed_runs_is_caught_up() {
+        let hourly = parse("0 0 * * * *").unwrap();
+        assert_eq!(
+            missed_run(&hourly, chrono_tz::UTC, Some(at(3, 0)), at(7, 30)),
+            Some(at(7, 0))
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use crate::randstr;
 use crate::types::*;
 use rocket::Request;
 use rocket::http::{Cookie, CookieJar, SameSite, Status};
@@ -16,6 +17,15 @@ pub struct Session {
 const SESSION_COOKIE: &str = "e2lsession";
 const OAUTH_STATE_COOKIE: &str = "e2loauthstate";
 const OAUTH_VERIFIER_COOKIE: &str = "e2loauthverifier";
+const REFERRAL_VISITOR_COOKIE: &str = "e2lref";
+const PENDING_TRANSFER_COOKIE: &str = "e2ltransfer";
+
+// A membership that was verified for `lichess_id` but is linked to another account.
+#[derive(Serialize, Deserialize)]
+pub struct PendingTransfer {
+    pub lichess_id: String,
+    pub org_id: String,
+}
 
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for Session {
@@ -77,3 +87,47 @@ pub fn pop_oauth_code_verifier(cookies: &CookieJar<'_>) -> Option<String> {
     cookies.remove_private(OAUTH_VERIFIER_COOKIE);
     cookie_value
 }
+
+// Identifies a visitor who followed the referral link before logging in, so their clicks can be
+// tied to their Lichess account later.
+pub fn referral_visitor(cookies: &CookieJar<'_>) -> Result<String, ErrorBox> {
+    if let Some(cookie) = cookies.get_private(REFERRAL_VISITOR_COOKIE) {
+        return Ok(cookie.value().to_string());
+    }
+    let visitor = randstr::random_string()?;
+    let mut visitor_cookie = Cookie::new(REFERRAL_VISITOR_COOKIE, visitor.clone());
+    visitor_cookie.set_max_age(Duration::days(90));
+    visitor_cookie.set_same_site(SameSite::Lax);
+    visitor_cookie.set_secure(true);
+    cookies.add_private(visitor_cookie);
+    Ok(visitor)
+}
+
+pub fn pop_referral_visitor(cookies: &CookieJar<'_>) -> Option<String> {
+    let cookie_value = cookies
+        .get_private(REFERRAL_VISITOR_COOKIE)
+        .map(|c| c.value().to_string());
+    cookies.remove_private(REFERRAL_VISITOR_COOKIE);
+    cookie_value
+}
+
+pub fn set_pending_transfer(
+    cookies: &CookieJar<'_>,
+    transfer: PendingTransfer,
+) -> Result<(), ErrorBox> {
+    let mut transfer_cookie =
+        Cookie::new(PENDING_TRANSFER_COOKIE, serde_json::to_string(&transfer)?);
+    transfer_cookie.set_max_age(Duration::minutes(10));
+    transfer_cookie.set_same_site(SameSite::Lax);
+    transfer_cookie.set_secure(true);
+    cookies.add_private(transfer_cookie);
+    Ok(())
+}
+
+pub fn pop_pending_transfer(cookies: &CookieJar<'_>) -> Option<PendingTransfer> {
+    let transfer = cookies
+        .get_private(PENDING_TRANSFER_COOKIE)
+        .and_then(|c| serde_json::from_str(c.value()).ok());
+    cookies.remove_private(PENDING_TRANSFER_COOKIE);
+    transfer
+}
@@ -1,16 +1,28 @@
-use crate::config::{Config, ExpiryConfig, OrgConfig};
-use crate::db::Membership;
+use crate::apikey;
+use crate::config::{Config, ExpiryConfig, GrandPrixSeries, OrgConfig};
+use crate::db::{ApiKeyRecord, LinkLockout, Membership, PastMembership, QueuedKick, ReferralStat};
+use crate::grandprix::SeriesStandings;
+use crate::i18n::{Language, Locale};
+use crate::lichess::TournamentKind;
+use crate::org::{self, MembershipState};
 use crate::session::Session;
+use crate::tournament::{TournamentExport, UnlinkedParticipant};
+use chrono_tz::Tz;
 use serde::Serialize;
 
 #[derive(Serialize)]
 pub struct BaseContext<'a> {
     pub org: &'a OrgConfig,
+    // For links to Lichess, which may be a development instance.
+    pub lichess_domain: &'a str,
+    pub lang: String,
+    pub languages: Vec<Language>,
 }
 
 #[derive(Serialize)]
 pub struct LoggedInContext<'a> {
-    pub org: &'a OrgConfig,
+    #[serde(flatten)]
+    pub base: BaseContext<'a>,
     pub lichess: String,
     pub admin: bool,
 }
@@ -28,19 +40,104 @@ pub struct LinkedContext<'a> {
     pub logged_in: LoggedInContext<'a>,
     pub org_id: String,
     pub exp_year: i32,
+    pub state: MembershipState,
     pub can_renew: bool,
-    pub exp_month: String,
-    pub exp_day: u32,
-    pub renew_month: String,
-    pub renew_day: u32,
+    pub expiry: String,
+    pub expiry_short: String,
+    pub renewal: String,
+}
+
+#[derive(Serialize)]
+pub struct TransferContext<'a> {
+    #[serde(flatten)]
+    pub logged_in: LoggedInContext<'a>,
+    pub org_id: String,
+    pub linked_to: String,
+}
+
+#[derive(Serialize)]
+pub struct UnlinkContext<'a> {
+    #[serde(flatten)]
+    pub logged_in: LoggedInContext<'a>,
+    pub org_id: String,
+}
+
+#[derive(Serialize)]
+pub struct NotLinkedContext<'a> {
+    #[serde(flatten)]
+    pub logged_in: LoggedInContext<'a>,
+    pub previous: Option<PastMembership>,
+}
+
+#[derive(Serialize)]
+pub struct MemberRow {
+    #[serde(flatten)]
+    pub membership: Membership,
+    pub state: MembershipState,
+}
+
+#[derive(Serialize)]
+pub struct ReferralRow {
+    #[serde(flatten)]
+    pub stat: ReferralStat,
+    pub clicks_percent: i64,
+    pub users_percent: i64,
+    pub conversions_percent: i64,
+}
+
+#[derive(Serialize)]
+pub struct ReferralChart {
+    pub total: i64,
+    pub by_month: Vec<ReferralRow>,
+    pub by_source: Vec<ReferralRow>,
 }
 
 #[derive(Serialize)]
 pub struct AdminContext<'a> {
     #[serde(flatten)]
     pub logged_in: LoggedInContext<'a>,
-    pub ref_count: i64,
-    pub members: Vec<Membership>,
+    pub referrals: ReferralChart,
+    pub members: Vec<MemberRow>,
+    pub history: Vec<PastMembership>,
+    pub lockouts: Vec<LinkLockout>,
+    pub failed_kicks: Vec<QueuedKick>,
+    pub api_keys: Vec<ApiKeyRecord>,
+    pub scopes: &'static [&'static str],
+}
+
+#[derive(Serialize)]
+pub struct ApiKeyCreatedContext<'a> {
+    #[serde(flatten)]
+    pub logged_in: LoggedInContext<'a>,
+    pub name: String,
+    pub key: String,
+}
+
+#[derive(Serialize)]
+pub struct TournamentContext<'a> {
+    #[serde(flatten)]
+    pub logged_in: LoggedInContext<'a>,
+    pub kind: &'static str,
+    pub tournament_id: String,
+    pub participants: usize,
+    pub games: usize,
+    pub rated_games: usize,
+    pub unlinked: Vec<UnlinkedParticipant>,
+}
+
+#[derive(Serialize)]
+pub struct GrandPrixIndexContext<'a> {
+    #[serde(flatten)]
+    pub base: BaseContext<'a>,
+    pub series: &'a [GrandPrixSeries],
+}
+
+#[derive(Serialize)]
+pub struct GrandPrixContext<'a> {
+    #[serde(flatten)]
+    pub base: BaseContext<'a>,
+    #[serde(flatten)]
+    pub series: SeriesStandings,
 }
 
 #[derive(Serialize)]
@@ -50,15 +147,28 @@ pub struct KickConfirmContext<'a> {
     pub who: String,
 }
 
-pub fn empty_context(config: &Config) -> BaseContext<'_> {
-    BaseContext { org: &config.org }
+pub fn empty_context<'a>(config: &'a Config, locale: &Locale) -> BaseContext<'a> {
+    BaseContext {
+        org: &config.org,
+        lichess_domain: &config.lichess.domain,
+        lang: locale.lang.clone(),
+        languages: locale.languages(),
+    }
 }
 
-pub fn make_logged_in_context<'a>(session: &Session, config: &'a Config) -> LoggedInContext<'a> {
+pub fn is_admin(session: &Session, config: &Config) -> bool {
+    session.lichess_id == config.lichess.team_admin
+}
+
+pub fn make_logged_in_context<'a>(
+    session: &Session,
+    config: &'a Config,
+    locale: &Locale,
+) -> LoggedInContext<'a> {
     LoggedInContext {
-        org: &config.org,
+        base: empty_context(config, locale),
         lichess: String::from(&session.lichess_username),
-        admin: session.lichess_id == config.lichess.team_admin,
+        admin: is_admin(session, config),
     }
 }
 
@@ -72,55 +182,166 @@ pub fn make_error_context<'a>(
     }
 }
 
+pub fn make_not_linked_context(
+    logged_in: LoggedInContext,
+    previous: Option<PastMembership>,
+) -> NotLinkedContext {
+    NotLinkedContext {
+        logged_in,
+        previous,
+    }
+}
+
+// Bar lengths are relative to the largest number of clicks in the same chart.
+fn make_referral_rows(stats: Vec<ReferralStat>) -> Vec<ReferralRow> {
+    let max = stats.iter().map(|s| s.clicks).max().unwrap_or(0).max(1);
+    stats
+        .into_iter()
+        .map(|stat| ReferralRow {
+            clicks_percent: stat.clicks * 100 / max,
+            users_percent: stat.users * 100 / max,
+            conversions_percent: stat.conversions * 100 / max,
+            stat,
+        })
+        .collect()
+}
+
+pub fn make_referral_chart(
+    total: i64,
+    by_month: Vec<ReferralStat>,
+    by_source: Vec<ReferralStat>,
+) -> ReferralChart {
+    ReferralChart {
+        total,
+        by_month: make_referral_rows(by_month),
+        by_source: make_referral_rows(by_source),
+    }
+}
+
 pub fn make_admin_context<'a>(
     logged_in: LoggedInContext<'a>,
-    ref_count: i64,
-    members: Vec<Membership>,
+    referrals: ReferralChart,
+    members: Vec<MemberRow>,
+    history: Vec<PastMembership>,
+    lockouts: Vec<LinkLockout>,
+    failed_kicks: Vec<QueuedKick>,
+    api_keys: Vec<ApiKeyRecord>,
 ) -> AdminContext<'a> {
     AdminContext {
         logged_in,
         members,
-        ref_count,
+        history,
+        referrals,
+        lockouts,
+        failed_kicks,
+        api_keys,
+        scopes: apikey::SCOPES,
     }
 }
 
-fn month_to_string(month: u32) -> String {
-    String::from(match month {
-        1 => "January",
-        2 => "February",
-        3 => "March",
-        4 => "April",
-        5 => "May",
-        6 => "June",
-        7 => "July",
-        8 => "August",
-        9 => "September",
-        10 => "October",
-        11 => "November",
-        12 => "December",
-        _ => "ERROR",
-    })
+pub fn make_api_key_created_context(
+    logged_in: LoggedInContext,
+    name: String,
+    key: String,
+) -> ApiKeyCreatedContext {
+    ApiKeyCreatedContext {
+        logged_in,
+        name,
+        key,
+    }
+}
+
+pub fn make_tournament_context(
+    logged_in: LoggedInContext,
+    kind: TournamentKind,
+    tournament_id: String,
+    export: TournamentExport,
+) -> TournamentContext {
+    TournamentContext {
+        logged_in,
+        kind: kind.as_str(),
+        tournament_id,
+        participants: export.participants,
+        games: export.games,
+        rated_games: export.rated_games.len(),
+        unlinked: export.unlinked,
+    }
+}
+
+pub fn make_grand_prix_index_context<'a>(
+    config: &'a Config,
+    locale: &Locale,
+) -> GrandPrixIndexContext<'a> {
+    GrandPrixIndexContext {
+        base: empty_context(config, locale),
+        series: &config.grand_prix.series,
+    }
+}
+
+pub fn make_grand_prix_context<'a>(
+    config: &'a Config,
+    locale: &Locale,
+    series: SeriesStandings,
+) -> GrandPrixContext<'a> {
+    GrandPrixContext {
+        base: empty_context(config, locale),
+        series,
+    }
+}
+
+pub fn make_member_row(membership: Membership, timezone: Tz, expiry: &ExpiryConfig) -> MemberRow {
+    MemberRow {
+        state: org::membership_state(
+            membership.exp_year,
+            timezone,
+            expiry.membership_month,
+            expiry.membership_day,
+        ),
+        membership,
+    }
 }
 
 pub fn make_linked_context<'a>(
     logged_in: LoggedInContext<'a>,
     org_id: String,
     exp_year: i32,
+    state: MembershipState,
     can_renew: bool,
     exp_config: &ExpiryConfig,
+    locale: &Locale,
 ) -> LinkedContext<'a> {
     LinkedContext {
         logged_in,
         org_id,
         exp_year,
+        state,
         can_renew,
-        exp_month: month_to_string(exp_config.membership_month),
-        exp_day: exp_config.membership_day,
-        renew_month: month_to_string(exp_config.renewal_month),
-        renew_day: exp_config.renewal_day,
+        expiry: locale.date(
+            exp_year,
+            exp_config.membership_month,
+            exp_config.membership_day,
+        ),
+        expiry_short: locale.day_month(exp_config.membership_month, exp_config.membership_day),
+        renewal: locale.day_month(exp_config.renewal_month, exp_config.renewal_day),
+    }
+}
+
+pub fn make_transfer_context(
+    logged_in: LoggedInContext,
+    org_id: String,
+    linked_to: String,
+) -> TransferContext {
+    TransferContext {
+        logged_in,
+        org_id,
+        linked_to,
     }
 }
 
+pub fn make_unlink_context(logged_in: LoggedInContext, org_id: String) -> UnlinkContext {
+    UnlinkContext { logged_in, org_id }
+}
+
 pub fn make_kick_confirm_context(logged_in: LoggedInContext, who: String) -> KickConfirmContext {
     KickConfirmContext { logged_in, who }
 }
@@ -9,3 +9,9 @@ pub fn append_line_to(filename: &str, line: &str) -> Result<(), ErrorBox> {
     writeln!(file, "[{}] {}", Utc::now(), line)?;
     Ok(())
 }
+
+// Prints `message` and appends it to `filename`, for background jobs.
+pub fn log_to(filename: &str, message: &str) {
+    append_line_to(filename, message).unwrap_or(());
+    println!("{}", message);
+}
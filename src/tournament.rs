@@ -0,0 +1,305 @@
+use crate::config::LichessConfig;
+use crate::db::OrgDbClient;
+use crate::lichess::{self, Game, TournamentKind};
+use crate::types::*;
+use chrono::DateTime;
+use chrono_tz::Tz;
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+
+// Games that never got going are not submitted for rating.
+const UNPLAYED_STATUSES: &[&str] = &["created", "started", "aborted", "noStart"];
+
+const CSV_HEADER: &str =
+    "game_id,date,member_id,lichess_id,colour,result,opponent_member_id,opponent_lichess_id";
+
+// One game from the point of view of a linked player.
+pub struct RatedGame {
+    pub game_id: String,
+    pub date: String,
+    pub member_id: String,
+    pub lichess_id: String,
+    pub colour: &'static str,
+    pub result: &'static str,
+    pub opponent_member_id: Option<String>,
+    pub opponent_lichess_id: String,
+}
+
+#[derive(Serialize)]
+pub struct UnlinkedParticipant {
+    pub rank: u32,
+    pub lichess_id: String,
+    pub score: f64,
+}
+
+pub struct TournamentExport {
+    pub participants: usize,
+    pub games: usize,
+    pub rated_games: Vec<RatedGame>,
+    pub unlinked: Vec<UnlinkedParticipant>,
+}
+
+// Lichess tournament IDs are short and alphanumeric.
+pub fn is_valid_id(tournament_id: &str) -> bool {
+    !tournament_id.is_empty()
+        && tournament_id.len() <= 16
+        && tournament_id.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+fn result_for(game: &Game, colour: &str) -> &'static str {
+    match game.winner.as_deref() {
+        None => "0.5",
+        Some(winner) if winner == colour => "1",
+        Some(_) => "0",
+    }
+}
+
+// One entry per game per linked player, skipping games that were never played and games against
+// anonymous or closed accounts.
+fn rated_games(
+    games: &[Game],
+    member_ids: &HashMap<String, String>,
+    timezone: Tz,
+) -> Vec<RatedGame> {
+    let mut rated_games = vec![];
+    for game in games
+        .iter()
+        .filter(|game| !UNPLAYED_STATUSES.contains(&game.status.as_str()))
+    {
+        let (Some(white), Some(black)) = (&game.players.white.user, &game.players.black.user)
+        else {
+            continue;
+        };
+        let date = DateTime::from_timestamp_millis(game.created_at)
+            .map(|time| {
+                time.with_timezone(&timezone)
+                    .date_naive()
+                    .format("%Y-%m-%d")
+                    .to_string()
+            })
+            .unwrap_or_default();
+        for (player, colour, opponent) in [(white, "white", black), (black, "black", white)] {
+            if let Some(member_id) = member_ids.get(&player.id) {
+                rated_games.push(RatedGame {
+                    game_id: game.id.clone(),
+                    date: date.clone(),
+                    member_id: member_id.clone(),
+                    lichess_id: player.id.clone(),
+                    colour,
+                    result: result_for(game, colour),
+                    opponent_member_id: member_ids.get(&opponent.id).cloned(),
+                    opponent_lichess_id: opponent.id.clone(),
+                });
+            }
+        }
+    }
+    rated_games
+}
+
+// Fetches the results and games of a finished tournament and maps its players to member IDs, or
+// returns None if Lichess does not know the tournament.
+pub async fn export(
+    db: &OrgDbClient,
+    http_client: &reqwest::Client,
+    lichess_config: &LichessConfig,
+    timezone: Tz,
+    kind: TournamentKind,
+    tournament_id: &str,
+) -> Result<Option<TournamentExport>, ErrorBox> {
+    let token = &lichess_config.personal_api_token;
+    let domain = &lichess_config.domain;
+    let Some(results) =
+        lichess::get_tournament_results(http_client, token, domain, kind, tournament_id).await?
+    else {
+        return Ok(None);
+    };
+    let Some(games) =
+        lichess::get_tournament_games(http_client, token, domain, kind, tournament_id).await?
+    else {
+        return Ok(None);
+    };
+
+    let mut lichess_ids: BTreeSet<String> = results
+        .iter()
+        .map(|result| result.username.to_lowercase())
+        .collect();
+    for game in &games {
+        for player in [&game.players.white, &game.players.black] {
+            if let Some(user) = &player.user {
+                lichess_ids.insert(user.id.clone());
+            }
+        }
+    }
+    let lichess_ids: Vec<String> = lichess_ids.into_iter().collect();
+    let member_ids: HashMap<String, String> = db
+        .get_members_for_lichess_ids(&lichess_ids)
+        .await?
+        .into_iter()
+        .map(|membership| (membership.lichess_id, membership.org_id))
+        .collect();
+
+    let rated_games = rated_games(&games, &member_ids, timezone);
+
+    let unlinked = results
+        .iter()
+        .filter(|result| !member_ids.contains_key(&result.username.to_lowercase()))
+        .map(|result| UnlinkedParticipant {
+            rank: result.rank,
+            lichess_id: result.username.to_lowercase(),
+            score: result.score.or(result.points).unwrap_or(0.0),
+        })
+        .collect();
+
+    Ok(Some(TournamentExport {
+        participants: results.len(),
+        games: games.len(),
+        rated_games,
+        unlinked,
+    }))
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// The rating submission: one line per game per linked player, with the opponent's member ID left
+// empty when the opponent is not linked.
+pub fn ratings_csv(export: &TournamentExport) -> String {
+    let mut csv = String::from(CSV_HEADER);
+    csv.push('\n');
+    for game in &export.rated_games {
+        let fields = [
+            game.game_id.as_str(),
+            game.date.as_str(),
+            game.member_id.as_str(),
+            game.lichess_id.as_str(),
+            game.colour,
+            game.result,
+            game.opponent_member_id.as_deref().unwrap_or(""),
+            game.opponent_lichess_id.as_str(),
+        ];
+        csv.push_str(
+            &fields
+                .iter()
+                .map(|field| csv_field(field))
+                .collect::<Vec<_>>()
+                .join(","),
+        );
+        csv.push('\n');
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn game(id: &str, status: &str, winner: Option<&str>, white: &str, black: &str) -> Game {
+        let user = |name: &str| {
+            if name.is_empty() {
+                serde_json::json!({})
+            } else {
+                serde_json::json!({ "user": { "id": name } })
+            }
+        };
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "createdAt": 1700000000000i64,
+            "status": status,
+            "winner": winner,
+            "players": { "white": user(white), "black": user(black) },
+        }))
+        .unwrap()
+    }
+
+    fn member_ids() -> HashMap<String, String> {
+        HashMap::from([
+            (String::from("alice"), String::from("1001")),
+            (String::from("bob"), String::from("1002")),
+        ])
+    }
+
+    #[test]
+    fn maps_linked_players_only() {
+        let games = [
+            game("g1", "mate", Some("white"), "alice", "bob"),
+            game("g2", "draw", None, "carol", "alice"),
+            game("g3", "resign", Some("black"), "carol", "dave"),
+        ];
+        let rated = rated_games(&games, &member_ids(), chrono_tz::UTC);
+        let rows: Vec<_> = rated
+            .iter()
+            .map(|game| {
+                (
+                    game.game_id.as_str(),
+                    game.member_id.as_str(),
+                    game.colour,
+                    game.result,
+                    game.opponent_member_id.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                ("g1", "1001", "white", "1", Some("1002")),
+                ("g1", "1002", "black", "0", Some("1001")),
+                ("g2", "1001", "black", "0.5", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_unplayed_and_anonymous_games() {
+        let games = [
+            game("g1", "aborted", None, "alice", "bob"),
+            game("g2", "noStart", Some("white"), "alice", "bob"),
+            game("g3", "mate", Some("white"), "alice", ""),
+        ];
+        assert!(rated_games(&games, &member_ids(), chrono_tz::UTC).is_empty());
+    }
+
+    #[test]
+    fn dates_games_in_the_organisation_timezone() {
+        let games = [game("g1", "mate", Some("white"), "alice", "bob")];
+        let utc = rated_games(&games, &member_ids(), chrono_tz::UTC);
+        let auckland = rated_games(&games, &member_ids(), chrono_tz::Pacific::Auckland);
+        assert_eq!(utc[0].date, "2023-11-14");
+        assert_eq!(auckland[0].date, "2023-11-15");
+    }
+
+    #[test]
+    fn csv_has_the_documented_column_order() {
+        let export = TournamentExport {
+            participants: 3,
+            games: 1,
+            rated_games: vec![RatedGame {
+                game_id: String::from("g1"),
+                date: String::from("2023-11-14"),
+                member_id: String::from("1001"),
+                lichess_id: String::from("alice"),
+                colour: "white",
+                result: "1",
+                opponent_member_id: None,
+                opponent_lichess_id: String::from("carol"),
+            }],
+            unlinked: vec![],
+        };
+        assert_eq!(
+            ratings_csv(&export),
+            "game_id,date,member_id,lichess_id,colour,result,opponent_member_id,opponent_lichess_id\n\
+            g1,2023-11-14,1001,alice,white,1,,carol\n"
+        );
+    }
+
+    #[test]
+    fn csv_quotes_fields_with_separators() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+}
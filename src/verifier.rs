@@ -0,0 +1,138 @@
+use crate::azolve;
+use crate::config::{AzolveConfig, Config, TestingConfig};
+use crate::db::OrgDbClient;
+use crate::types::*;
+use chrono::Datelike;
+use reqwest::Client;
+use subtle::ConstantTimeEq;
+
+pub enum Verification {
+    Verified,
+    VerifiedInTestMode,
+    Rejected,
+}
+
+pub enum Lookup {
+    // The backend cannot look members up without their password.
+    Unsupported,
+    NotFound,
+    ExpiryYear(i32),
+}
+
+fn is_test_member(testing: &TestingConfig, member_id: &str, member_password: &str) -> bool {
+    let id_matches = member_id.as_bytes().ct_eq(testing.member_id.as_bytes());
+    let password_matches = member_password
+        .as_bytes()
+        .ct_eq(testing.password.as_bytes());
+    testing.enable && bool::from(id_matches & password_matches)
+}
+
+// Checks a member's credentials on behalf of `lichess_id`. Test mode verifications are audited.
+pub async fn verify_user(
+    db: &OrgDbClient,
+    http_client: &Client,
+    config: &Config,
+    lichess_id: &str,
+    member_id: &str,
+    member_password: &str,
+) -> Result<Verification, ErrorBox> {
+    if is_test_member(&config.testing, member_id, member_password) {
+        println!("Test mode verification of {} by {}", member_id, lichess_id);
+        db.audit("test_verification", Some(lichess_id), Some(member_id), "")
+            .await?;
+        return Ok(Verification::VerifiedInTestMode);
+    }
+
+    let verified = azolve::verify_user(
+        http_client,
+        member_id,
+        member_password,
+        &config.azolve.api,
+        &config.azolve.api_pwd,
+        &config.azolve.api_token,
+        &config.org.authentication_secret,
+    )
+    .await?;
+    Ok(if verified {
+        Verification::Verified
+    } else {
+        Verification::Rejected
+    })
+}
+
+pub fn can_look_up(azolve: &AzolveConfig, testing: &TestingConfig) -> bool {
+    !azolve.lookup_object.is_empty() || testing.enable
+}
+
+// Finds out until when a membership runs, without the member's password. In test mode the test
+// member always counts as renewed until `test_exp_year`, and every such lookup is audited.
+#[allow(clippy::too_many_arguments)]
+pub async fn look_up_member(
+    db: &OrgDbClient,
+    http_client: &Client,
+    azolve: &AzolveConfig,
+    testing: &TestingConfig,
+    lichess_id: &str,
+    member_id: &str,
+    test_exp_year: i32,
+) -> Result<Lookup, ErrorBox> {
+    if testing.enable && bool::from(member_id.as_bytes().ct_eq(testing.member_id.as_bytes())) {
+        println!("Test mode lookup of {} for {}", member_id, lichess_id);
+        db.audit(
+            "test_lookup",
+            Some(lichess_id),
+            Some(member_id),
+            &format!("exp_year={}", test_exp_year),
+        )
+        .await?;
+        return Ok(Lookup::ExpiryYear(test_exp_year));
+    }
+    if azolve.lookup_object.is_empty() {
+        return Ok(Lookup::Unsupported);
+    }
+
+    let expiry = azolve::lookup_expiry(
+        http_client,
+        member_id,
+        &azolve.api,
+        &azolve.api_pwd,
+        &azolve.api_token,
+        &azolve.lookup_object,
+    )
+    .await?;
+    Ok(match expiry {
+        Some(date) => Lookup::ExpiryYear(date.year()),
+        None => Lookup::NotFound,
+    })
+}
+
+pub fn can_write_back(azolve: &AzolveConfig) -> bool {
+    !azolve.write_object.is_empty()
+}
+
+// Records in the membership backend which Lichess account a member linked; an empty `lichess_id`
+// clears it.
+pub async fn write_lichess_id(
+    http_client: &Client,
+    azolve: &AzolveConfig,
+    member_id: &str,
+    lichess_id: &str,
+) -> Result<(), ErrorBox> {
+    if !can_write_back(azolve) {
+        return Err("azolve.write_object is not set".into());
+    }
+    let written = azolve::write_lichess_id(
+        http_client,
+        member_id,
+        lichess_id,
+        &azolve.api,
+        &azolve.api_pwd,
+        &azolve.api_token,
+        &azolve.write_object,
+    )
+    .await?;
+    if !written {
+        return Err(format!("the membership backend did not accept {}", member_id).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,216 @@
+use crate::config::WebhookConfig;
+use crate::db::{self, Membership, OrgDbClient, QueuedWebhook};
+use crate::leader::Leadership;
+use crate::randstr;
+use crate::retry;
+use crate::textlog;
+use crate::types::*;
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use rocket::tokio::time::sleep;
+use serde::Serialize;
+use sha2::Sha256;
+use std::fmt::Write;
+
+pub const LINKED: &str = "linked";
+pub const RENEWED: &str = "renewed";
+pub const TRANSFERRED: &str = "transferred";
+pub const KICKED: &str = "kicked";
+pub const UNLINKED: &str = "unlinked";
+
+pub const EVENT_HEADER: &str = "X-Org2Lichess-Event";
+pub const TIMESTAMP_HEADER: &str = "X-Org2Lichess-Timestamp";
+pub const SIGNATURE_HEADER: &str = "X-Org2Lichess-Signature";
+
+const QUEUE_BATCH_SIZE: i64 = 50;
+
+// The JSON body of a webhook request. `id` stays the same across retries, so receivers can ignore
+// events they have already handled.
+#[derive(Serialize)]
+struct Event<'a> {
+    id: String,
+    event: &'a str,
+    at: DateTime<Utc>,
+    org_id: &'a str,
+    lichess_id: &'a str,
+    exp_year: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    previous_lichess_id: Option<&'a str>,
+}
+
+// Queues `event` about `membership` for every configured endpoint.
+pub async fn notify(
+    db: &OrgDbClient,
+    config: &WebhookConfig,
+    event: &str,
+    membership: &Membership,
+    previous_lichess_id: Option<&str>,
+) -> Result<(), ErrorBox> {
+    if config.urls.is_empty() {
+        return Ok(());
+    }
+    let payload = serde_json::to_string(&Event {
+        id: randstr::random_string()?[..16].to_string(),
+        event,
+        at: Utc::now(),
+        org_id: &membership.org_id,
+        lichess_id: &membership.lichess_id,
+        exp_year: membership.exp_year,
+        previous_lichess_id,
+    })?;
+    for url in &config.urls {
+        db.enqueue_webhook(url, event, &membership.lichess_id, &payload)
+            .await?;
+    }
+    Ok(())
+}
+
+// Hex encoded HMAC-SHA256 of "<timestamp>.<body>".
+pub fn sign(secret: &str, timestamp: &str, body: &str) -> Result<String, ErrorBox> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    Ok(mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{:02x}", byte);
+            hex
+        }))
+}
+
+async fn deliver(
+    http_client: &reqwest::Client,
+    config: &WebhookConfig,
+    webhook: &QueuedWebhook,
+) -> Result<(), ErrorBox> {
+    let timestamp = Utc::now().timestamp().to_string();
+    let signature = sign(&config.secret, &timestamp, &webhook.payload)?;
+    let response = http_client
+        .post(&webhook.url)
+        .timeout(std::time::Duration::from_secs(config.timeout_seconds))
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(EVENT_HEADER, &webhook.event)
+        .header(TIMESTAMP_HEADER, &timestamp)
+        .header(SIGNATURE_HEADER, format!("sha256={}", signature))
+        .body(webhook.payload.clone())
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(format!("{} responded with {}", &webhook.url, response.status()).into());
+    }
+    Ok(())
+}
+
+async fn fail_webhook(
+    db: &OrgDbClient,
+    config: &WebhookConfig,
+    webhook: &QueuedWebhook,
+    error: &str,
+) -> Result<(), ErrorBox> {
+    let attempts = webhook.attempts + 1;
+    textlog::append_line_to(
+        "webhook.error.log",
+        &format!(
+            "Could not deliver {} event {} (attempt {}): {}",
+            &webhook.event, webhook.id, attempts, error
+        ),
+    )
+    .unwrap_or(());
+    println!(
+        "Could not deliver {} event {}: {}",
+        &webhook.event, webhook.id, error
+    );
+
+    if attempts >= config.max_attempts {
+        db.reschedule_webhook(webhook.id, attempts, Utc::now(), error)
+            .await?;
+        db.set_webhook_state(webhook.id, db::WEBHOOK_FAILED, error)
+            .await?;
+    } else {
+        let backoff = retry::backoff_delay(
+            attempts,
+            config.base_retry_seconds,
+            retry::MAX_RETRY_SECONDS,
+        );
+        db.reschedule_webhook(
+            webhook.id,
+            attempts,
+            Utc::now() + Duration::seconds(backoff),
+            error,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+pub async fn process_webhook_queue(
+    db: &OrgDbClient,
+    http_client: &reqwest::Client,
+    config: &WebhookConfig,
+    leadership: &Leadership,
+) -> Result<(), ErrorBox> {
+    loop {
+        let due = db.get_due_webhooks(QUEUE_BATCH_SIZE).await?;
+        if due.is_empty() {
+            return Ok(());
+        }
+        for webhook in &due {
+            if !leadership.is_leader() {
+                return Ok(());
+            }
+            match deliver(http_client, config, webhook).await {
+                Ok(()) => {
+                    db.set_webhook_state(webhook.id, db::WEBHOOK_DONE, "")
+                        .await?;
+                }
+                Err(e) => fail_webhook(db, config, webhook, &e.to_string()).await?,
+            }
+        }
+    }
+}
+
+pub fn launch(db_client: OrgDbClient, config: WebhookConfig, leadership: Leadership) {
+    rocket::tokio::task::spawn(async move {
+        let http_client = reqwest::Client::new();
+
+        loop {
+            if leadership.is_leader()
+                && let Err(e) =
+                    process_webhook_queue(&db_client, &http_client, &config, &leadership).await
+            {
+                textlog::append_line_to(
+                    "webhook.error.log",
+                    &format!("Could not process webhook queue: {}", e),
+                )
+                .unwrap_or(());
+                println!("Could not process webhook queue: {}", e);
+            }
+
+            sleep(std::time::Duration::from_secs(config.poll_seconds)).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_matches_hmac_sha256_of_timestamp_and_body() {
+        assert_eq!(
+            sign("whsec_test", "1700000000", r#"{"event":"linked"}"#).unwrap(),
+            "2ab3fbb64242fd8ab0207fe5c675ad5e60da455515ec974f68a6c0aa0a17c6d1"
+        );
+    }
+
+    #[test]
+    fn sign_depends_on_the_timestamp() {
+        assert_ne!(
+            sign("whsec_test", "1700000000", "{}").unwrap(),
+            sign("whsec_test", "1700000001", "{}").unwrap()
+        );
+    }
+}
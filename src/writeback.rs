@@ -0,0 +1,132 @@
+use crate::config::{AzolveConfig, WritebackConfig};
+use crate::db::{self, OrgDbClient, QueuedWriteback};
+use crate::leader::Leadership;
+use crate::retry;
+use crate::textlog;
+use crate::types::*;
+use crate::verifier;
+use chrono::{Duration, Utc};
+use rocket::tokio::time::sleep;
+
+const QUEUE_BATCH_SIZE: i64 = 50;
+
+#[derive(Clone)]
+pub struct WritebackSettings {
+    pub azolve: AzolveConfig,
+    pub queue: WritebackConfig,
+}
+
+// Queues writing `lichess_id` to the member's record in the membership backend, or clearing it
+// when `lichess_id` is empty. Does nothing unless the backend supports it.
+pub async fn enqueue(
+    db: &OrgDbClient,
+    azolve: &AzolveConfig,
+    org_id: &str,
+    lichess_id: &str,
+) -> Result<(), ErrorBox> {
+    if verifier::can_write_back(azolve) {
+        db.enqueue_writeback(org_id, lichess_id).await?;
+    }
+    Ok(())
+}
+
+async fn fail_writeback(
+    db: &OrgDbClient,
+    settings: &WritebackSettings,
+    writeback: &QueuedWriteback,
+    error: &str,
+) -> Result<(), ErrorBox> {
+    let attempts = writeback.attempts + 1;
+    textlog::append_line_to(
+        "writeback.error.log",
+        &format!(
+            "Could not write back {} for {} (attempt {}): {}",
+            &writeback.lichess_id, &writeback.org_id, attempts, error
+        ),
+    )
+    .unwrap_or(());
+    println!(
+        "Could not write back {} for {}: {}",
+        &writeback.lichess_id, &writeback.org_id, error
+    );
+
+    if attempts >= settings.queue.max_attempts {
+        db.reschedule_writeback(writeback, attempts, Utc::now(), error)
+            .await?;
+        db.set_writeback_state(writeback, db::WRITEBACK_FAILED, error)
+            .await?;
+    } else {
+        let backoff = retry::backoff_delay(
+            attempts,
+            settings.queue.base_retry_seconds,
+            retry::MAX_RETRY_SECONDS,
+        );
+        db.reschedule_writeback(
+            writeback,
+            attempts,
+            Utc::now() + Duration::seconds(backoff),
+            error,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+// Returns how many Lichess IDs were written.
+pub async fn process_writeback_queue(
+    db: &OrgDbClient,
+    http_client: &reqwest::Client,
+    settings: &WritebackSettings,
+    leadership: &Leadership,
+) -> Result<u64, ErrorBox> {
+    let mut written = 0;
+    loop {
+        let due = db.get_due_writebacks(QUEUE_BATCH_SIZE).await?;
+        if due.is_empty() {
+            return Ok(written);
+        }
+        for writeback in &due {
+            if !leadership.is_leader() {
+                return Ok(written);
+            }
+            match verifier::write_lichess_id(
+                http_client,
+                &settings.azolve,
+                &writeback.org_id,
+                &writeback.lichess_id,
+            )
+            .await
+            {
+                Ok(()) => {
+                    db.set_writeback_state(writeback, db::WRITEBACK_DONE, "")
+                        .await?;
+                    written += 1;
+                }
+                Err(e) => fail_writeback(db, settings, writeback, &e.to_string()).await?,
+            }
+            sleep(std::time::Duration::from_millis(settings.queue.delay_ms)).await;
+        }
+    }
+}
+
+pub fn launch(db_client: OrgDbClient, settings: WritebackSettings, leadership: Leadership) {
+    rocket::tokio::task::spawn(async move {
+        let http_client = reqwest::Client::new();
+
+        loop {
+            if leadership.is_leader()
+                && let Err(e) =
+                    process_writeback_queue(&db_client, &http_client, &settings, &leadership).await
+            {
+                textlog::append_line_to(
+                    "writeback.error.log",
+                    &format!("Could not process writeback queue: {}", e),
+                )
+                .unwrap_or(());
+                println!("Could not process writeback queue: {}", e);
+            }
+
+            sleep(std::time::Duration::from_secs(settings.queue.poll_seconds)).await;
+        }
+    });
+}